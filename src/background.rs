@@ -0,0 +1,103 @@
+use image::{DynamicImage, GenericImageView, ImageBuffer, Rgb};
+
+use crate::structs::{Background, HexColor};
+
+/// Color used by `--background auto` when the border has no visible pixels, e.g. around a cutout.
+const FALLBACK: HexColor = HexColor([255, 255, 255]);
+
+/// Composites the image over a solid color and drops its alpha channel. 16-bit images stay 16-bit, everything else
+/// comes out as 8-bit RGB. Blending happens on the stored values, the way viewers show transparency.
+pub fn flatten(img: &DynamicImage, background: HexColor) -> DynamicImage {
+    let blend = |value: f32, background: f32, alpha: f32| value * alpha + background * (1.0 - alpha);
+    match img {
+        DynamicImage::ImageLuma16(_) | DynamicImage::ImageLumaA16(_) | DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgba16(_) => {
+            let background = background.0.map(|channel| channel as f32 * 257.0);
+            let rgba = img.to_rgba16();
+            DynamicImage::ImageRgb16(ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+                let pixel = rgba.get_pixel(x, y).0;
+                let alpha = pixel[3] as f32 / u16::MAX as f32;
+                Rgb([0, 1, 2].map(|channel| blend(pixel[channel] as f32, background[channel], alpha).round() as u16))
+            }))
+        }
+        _ => {
+            let background = background.0.map(|channel| channel as f32);
+            let rgba = img.to_rgba8();
+            DynamicImage::ImageRgb8(ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+                let pixel = rgba.get_pixel(x, y).0;
+                let alpha = pixel[3] as f32 / u8::MAX as f32;
+                Rgb([0, 1, 2].map(|channel| blend(pixel[channel] as f32, background[channel], alpha).round() as u8))
+            }))
+        }
+    }
+}
+
+/// The color transparent areas are flattened onto: the configured one, or with `auto` the per-channel median of the
+/// mostly opaque pixels along the image border, so a matte blends into the surrounding backdrop. The median ignores
+/// content that touches the edge.
+pub fn background_color(img: &DynamicImage, background: Background) -> HexColor {
+    match background {
+        Background::Color(color) => color,
+        Background::Auto => border_color(img).unwrap_or(FALLBACK)
+    }
+}
+
+/// Per-channel median of the mostly opaque pixels along the image border, `None` when the border is transparent.
+pub fn border_color(img: &DynamicImage) -> Option<HexColor> {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let border = (0..width).flat_map(|x| [(x, 0), (x, height - 1)])
+        .chain((1..height.saturating_sub(1)).flat_map(|y| [(0, y), (width - 1, y)]));
+    let pixels: Vec<[u8; 4]> = border.map(|(x, y)| img.get_pixel(x, y).0).filter(|pixel| pixel[3] >= 128).collect();
+    if pixels.is_empty() {
+        return None;
+    }
+    Some(HexColor([0, 1, 2].map(|channel| {
+        let mut values: Vec<u8> = pixels.iter().map(|pixel| pixel[channel]).collect();
+        let middle = values.len() / 2;
+        *values.select_nth_unstable(middle).1
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{LumaA, Rgba, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn transparency_blends_into_the_background_at_the_source_depth() {
+        let blue = HexColor([0, 0, 255]);
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(3, 1, |x, _| Rgba([255, 0, 0, [255, 128, 0][x as usize]])));
+        let flattened = flatten(&img, blue).into_rgb8();
+        assert_eq!(flattened.pixels().map(|pixel| pixel.0).collect::<Vec<_>>(), [[255, 0, 0], [128, 0, 127], [0, 0, 255]]);
+
+        let img = DynamicImage::ImageRgba16(ImageBuffer::from_pixel(1, 1, Rgba([u16::MAX, 0, 0, 0])));
+        let flattened = flatten(&img, blue);
+        assert_eq!(flattened.color(), image::ColorType::Rgb16);
+        assert_eq!(flattened.into_rgb16().get_pixel(0, 0).0, [0, 0, u16::MAX]);
+
+        let gray = DynamicImage::ImageLumaA8(ImageBuffer::from_pixel(1, 1, LumaA([200, 0])));
+        assert_eq!(flatten(&gray, blue).as_rgb8().map(|img| img.get_pixel(0, 0).0), Some([0, 0, 255]));
+    }
+
+    #[test]
+    fn the_border_median_ignores_transparent_pixels_and_content_at_the_edge() {
+        // a gray backdrop with a transparent hole in the middle, a red product touching the bottom edge
+        // and a transparent corner
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(10, 8, |x, y| match (x, y) {
+            (0, 0) => Rgba([0, 0, 0, 0]),
+            (3..=5, 5..=7) => Rgba([255, 0, 0, 255]),
+            (3..=6, 2..=4) => Rgba([0, 0, 0, 0]),
+            _ => Rgba([90, 100, 110, 255])
+        }));
+        assert_eq!(border_color(&img), Some(HexColor([90, 100, 110])));
+        assert_eq!(background_color(&img, Background::Auto), HexColor([90, 100, 110]));
+        assert_eq!(background_color(&img, Background::Color(HexColor([1, 2, 3]))), HexColor([1, 2, 3]));
+
+        let cutout = DynamicImage::ImageRgba8(RgbaImage::from_fn(10, 8, |x, y| Rgba([255, 0, 0, if (2..8).contains(&x) && (2..6).contains(&y) { 255 } else { 0 }])));
+        assert_eq!(border_color(&cutout), None);
+        assert_eq!(background_color(&cutout, Background::Auto), FALLBACK);
+    }
+}
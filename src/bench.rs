@@ -0,0 +1,139 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use image::{ImageBuffer, Rgb, RgbImage};
+use rayon::prelude::*;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::process::{estimate_memory, process_image_from_path};
+use crate::scheduler::{for_each_bounded, Limits, Scheduler};
+use crate::stages;
+use crate::stages::STAGES;
+use crate::state::settings_hash;
+use crate::structs::Args;
+
+/// Generates a reproducible synthetic image set in a temporary directory, runs the regular pipeline over it
+/// with the current settings and reports per-stage timings, throughput and peak memory.
+pub fn run(args: &Args, sizes: &str, formats: &str, count: usize, seed: u64, keep: bool) {
+    let sizes: Vec<(u32, u32)> = match sizes.split(',').map(parse_size).collect() {
+        Some(sizes) => sizes,
+        None => {
+            error!("Invalid --sizes '{}', expected e.g. 3000x4000,6000x4000", sizes);
+            return;
+        }
+    };
+    let formats: Vec<&str> = formats.split("|").collect();
+    let directory = std::env::temp_dir().join(format!("blipb-bench-{}", Uuid::new_v4()));
+    let input = directory.join("input");
+    let output = directory.join("output");
+    fs::create_dir_all(&input).expect("Unable to create the benchmark input directory.");
+    fs::create_dir_all(&output).expect("Unable to create the benchmark output directory.");
+
+    let jobs: Vec<(u32, u32, &str, usize)> = sizes
+        .iter()
+        .flat_map(|&(width, height)| formats.iter().flat_map(move |&format| (0..count).map(move |index| (width, height, format, index))))
+        .collect();
+    println!("Generating {} synthetic images in {}", jobs.len(), directory.display());
+    let files: Vec<PathBuf> = jobs
+        .par_iter()
+        .enumerate()
+        .filter_map(|(number, &(width, height, format, index))| {
+            let path = input.join(format!("bench-{}x{}-{}.{}", width, height, index, format));
+            match synthetic_image(width, height, seed.wrapping_add(number as u64)).save(&path) {
+                Ok(_) => Some(path),
+                Err(error) => {
+                    error!(file = %path.display(), "Unable to generate: {}", error);
+                    None
+                }
+            }
+        })
+        .collect();
+
+    let bench_args = Args {
+        input: format!("{}/", input.display()),
+        output: format!("{}/", output.display()),
+        force: true,
+        ..args.clone()
+    };
+    let scheduler = Scheduler::new(Limits::from_args(&bench_args));
+    // start the peak RSS measurement after generation (Linux only, ignored elsewhere)
+    let _result = fs::write("/proc/self/clear_refs", "5");
+    stages::reset();
+    let processed = AtomicUsize::new(0);
+    let start = Instant::now();
+    let settings_hash = settings_hash(&bench_args);
+    for_each_bounded(&files, &scheduler, |path| estimate_memory(path, &bench_args, &scheduler), |path| {
+        if process_image_from_path(path, &bench_args, &settings_hash, &scheduler).is_some() {
+            processed.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    let elapsed = start.elapsed();
+
+    let processed = processed.into_inner();
+    let megapixels: f64 = files.iter().map(|path| megapixels(path)).sum();
+    let seconds = elapsed.as_secs_f64();
+    println!("\n:::::Benchmark:::::\nimages: {} processed, {} failed\nsettings: max width {}, aspect ratio {}, resizer {:?}, fast decode {}, threads {}",
+             processed, files.len() - processed, args.max_width, args.aspect_ratio, args.resizer, args.fast_decode,
+             args.threads.map(|threads| threads.to_string()).unwrap_or(String::from("all")));
+    println!("wall time: {:.2}s\nthroughput: {:.2} images/s, {:.1} megapixels/s", seconds, processed as f64 / seconds, megapixels / seconds);
+    match peak_rss() {
+        Some(bytes) => println!("peak RSS: {} MiB", bytes / (1 << 20)),
+        None => println!("peak RSS: n/a")
+    }
+
+    let stage_total: Duration = STAGES.iter().map(|&stage| stages::totals(stage).0).sum();
+    println!("\n{:<10}{:>12}{:>14}{:>8}", "stage", "total", "per image", "share");
+    for stage in STAGES {
+        let (total, runs) = stages::totals(stage);
+        let per_image = if runs > 0 { total / runs as u32 } else { Duration::ZERO };
+        let share = if stage_total.is_zero() { 0.0 } else { total.as_secs_f64() / stage_total.as_secs_f64() * 100.0 };
+        println!("{:<10}{:>11.2}s{:>12.1}ms{:>7.1}%", stage.name(), total.as_secs_f64(), per_image.as_secs_f64() * 1000.0, share);
+    }
+
+    if keep {
+        println!("\nKept the benchmark images in {}", directory.display());
+    } else {
+        let _result = fs::remove_dir_all(&directory);
+    }
+}
+
+fn parse_size(size: &str) -> Option<(u32, u32)> {
+    let (width, height) = size.trim().split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+/// Gradients, a checkerboard and per-pixel noise from a seeded generator, so every run and machine
+/// compresses and resizes the same content.
+fn synthetic_image(width: u32, height: u32, seed: u64) -> RgbImage {
+    let mut state = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    ImageBuffer::from_fn(width, height, |x, y| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        let noise = (state >> 59) as u8;
+        let checker = if (x / 97 + y / 97) % 2 == 0 { 48 } else { 0 };
+        Rgb([
+            ((x as u64 * 200 / width as u64) as u8).wrapping_add(checker).wrapping_add(noise),
+            ((y as u64 * 200 / height as u64) as u8).wrapping_add(noise),
+            (((x + y) as u64 * 100 / (width + height) as u64) as u8).wrapping_add(checker),
+        ])
+    })
+}
+
+fn megapixels(path: &Path) -> f64 {
+    image::image_dimensions(path).map(|(width, height)| width as f64 * height as f64 / 1_000_000.0).unwrap_or(0.0)
+}
+
+#[cfg(target_os = "linux")]
+fn peak_rss() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peak_rss() -> Option<u64> {
+    None
+}
@@ -0,0 +1,309 @@
+use std::borrow::Cow;
+use std::fs;
+
+use image::{ColorType, DynamicImage, GenericImageView, ImageBuffer};
+use img_parts::jpeg::{markers, JpegSegment};
+use img_parts::{Bytes, DynImage, ImageICC};
+use moxcms::{CmsError, ColorProfile, DataColorSpace, Layout, ToneReprCurve, Transform16BitExecutor, TransformOptions};
+use tracing::debug;
+
+use crate::structs::{Args, EmbedProfile, OutputProfile};
+
+const ICC_SIGNATURE: &[u8] = b"ICC_PROFILE\0";
+
+/// Largest piece of a profile in one JPEG APP2 segment: the segment length limit less the signature and sequence bytes.
+const ICC_SEGMENT_SIZE: usize = 65_519;
+
+/// The ICC profile embedded in an encoded JPEG or PNG.
+pub fn icc_profile(bytes: &[u8]) -> Option<Vec<u8>> {
+    let image = DynImage::from_bytes(Bytes::copy_from_slice(bytes)).ok()??;
+    image.icc_profile().map(|profile| profile.to_vec())
+}
+
+/// Converts the decoded pixels from the source profile (sRGB when there is none) to the `--color-profile` of the output.
+/// Profiles of other color spaces such as CMYK don't describe the RGB pixels the decoder returns and are ignored.
+pub fn convert_colors(image: DynamicImage, source_icc: Option<&[u8]>, args: &Args) -> Result<DynamicImage, String> {
+    let target = match target_profile(&args.color_profile) {
+        Some(target) => target,
+        None => return Ok(image)
+    };
+    match source_icc {
+        // untagged images are taken as sRGB and need no conversion to it
+        None if args.color_profile == OutputProfile::Srgb => Ok(image),
+        source_icc => convert(image, source_icc, &target)
+    }
+}
+
+/// Converts the pixels to sRGB for display, as the UI shows them.
+#[cfg(feature = "ui")]
+pub fn convert_to_srgb(image: DynamicImage, source_icc: Option<&[u8]>) -> Result<DynamicImage, String> {
+    match source_icc {
+        Some(source_icc) => convert(image, Some(source_icc), &ColorProfile::new_srgb()),
+        None => Ok(image)
+    }
+}
+
+/// The profile to embed in an output whose pixels are of type `color`, if any. `auto` embeds every profile but sRGB,
+/// which viewers assume anyway. The source profile of `source` is only embedded when it describes those pixels,
+/// RGB or gray for gray ones; a CMYK profile, or a gray one on pixels flattened to RGB, is left out.
+pub fn output_profile(source_icc: Option<&[u8]>, color: ColorType, args: &Args) -> Result<Option<Vec<u8>>, String> {
+    let embed = match args.embed_profile {
+        EmbedProfile::Never => false,
+        EmbedProfile::Always => true,
+        EmbedProfile::Auto => args.color_profile != OutputProfile::Srgb
+    };
+    if !embed {
+        return Ok(None);
+    }
+    match &args.color_profile {
+        OutputProfile::Source => Ok(source_icc.filter(|icc| describes(icc, color)).map(<[u8]>::to_vec)),
+        OutputProfile::File(file) => Ok(Some(file.bytes.to_vec())),
+        profile => built_in(profile).encode().map(Some).map_err(|error| error.to_string())
+    }
+}
+
+fn describes(icc: &[u8], color: ColorType) -> bool {
+    let color_space = if color.channel_count() <= 2 { DataColorSpace::Gray } else { DataColorSpace::Rgb };
+    match ColorProfile::new_from_slice(icc) {
+        Ok(profile) if profile.color_space == color_space => true,
+        _ => {
+            debug!("Not embedding the source profile, which doesn't describe {:?} pixels", color);
+            false
+        }
+    }
+}
+
+/// The profile the pixels are in after `convert_colors`, whose tone curves `--linear-resize` undoes.
+/// Sources without a usable profile are taken as sRGB.
+pub fn working_profile(source_icc: Option<&[u8]>, args: &Args) -> ColorProfile {
+    let profile = match &args.color_profile {
+        OutputProfile::Source => source_icc.and_then(|icc| ColorProfile::new_from_slice(icc).ok()),
+        profile => target_profile(profile).map(Cow::into_owned)
+    };
+    profile.unwrap_or_else(ColorProfile::new_srgb)
+}
+
+/// Runs `resize` in linear light: the tone curves of `profile` are undone into a 16-bit buffer, the pixels resampled,
+/// and the curves and the bit depth of the image restored, so fine detail keeps its brightness.
+pub fn resize_linear(img: &DynamicImage, profile: &ColorProfile, resize: impl FnOnce(&DynamicImage) -> DynamicImage) -> Result<DynamicImage, String> {
+    let gray = profile.color_space == DataColorSpace::Gray && img.color().channel_count() <= 2;
+    if !(profile.is_matrix_shaper() || gray && profile.gray_trc.is_some()) {
+        return Err(String::from("the color profile has no tone curves"));
+    }
+    let mut linear = profile.clone();
+    // an empty curve is the identity
+    let identity = Some(ToneReprCurve::Lut(Vec::new()));
+    linear.red_trc = identity.clone();
+    linear.green_trc = identity.clone();
+    linear.blue_trc = identity.clone();
+    linear.gray_trc = identity;
+    // the curves of the profile, not a transfer function the CICP tag may name
+    linear.cicp = None;
+    let options = TransformOptions { allow_use_cicp_transfer: false, ..TransformOptions::default() };
+    let layout = match (gray, img.color().has_alpha()) {
+        (true, false) => Layout::Gray,
+        (true, true) => Layout::GrayAlpha,
+        (false, false) => Layout::Rgb,
+        (false, true) => Layout::Rgba
+    };
+    let to_linear = profile.create_transform_16bit(layout, &linear, layout, options).map_err(|error| error.to_string())?;
+    let from_linear = linear.create_transform_16bit(layout, profile, layout, options).map_err(|error| error.to_string())?;
+    let linear_image = transform_16bit(img.clone(), layout, to_linear.as_ref()).map_err(|error| error.to_string())?;
+    let resized = transform_16bit(resize(&linear_image), layout, from_linear.as_ref()).map_err(|error| error.to_string())?;
+    Ok(with_color_type_of(resized, img))
+}
+
+/// Writes the profile into the encoded file at `path`, replacing any it has.
+pub fn embed_profile(path: &str, profile: &[u8]) -> Result<(), String> {
+    let bytes = fs::read(path).map_err(|error| error.to_string())?;
+    let mut image = match DynImage::from_bytes(Bytes::from(bytes)).map_err(|error| error.to_string())? {
+        Some(image) => image,
+        None => return Err(String::from("unsupported image format"))
+    };
+    match &mut image {
+        DynImage::Jpeg(jpeg) => {
+            let segments = jpeg.segments_mut();
+            segments.retain(|segment| !(segment.marker() == markers::APP2 && segment.contents().starts_with(ICC_SIGNATURE)));
+            // after the JFIF header and the EXIF/XMP segments, where readers expect it
+            let position = segments.iter().take_while(|segment| matches!(segment.marker(), markers::APP0 | markers::APP1)).count();
+            let chunks: Vec<&[u8]> = profile.chunks(ICC_SEGMENT_SIZE).collect();
+            for (index, chunk) in chunks.iter().enumerate().rev() {
+                let contents = [ICC_SIGNATURE, &[index as u8 + 1, chunks.len() as u8], chunk].concat();
+                segments.insert(position, JpegSegment::new_with_contents(markers::APP2, Bytes::from(contents)));
+            }
+        }
+        image => image.set_icc_profile(Some(Bytes::copy_from_slice(profile)))
+    }
+    let mut buffer = Vec::new();
+    image.encoder().write_to(&mut buffer).map_err(|error| error.to_string())?;
+    fs::write(path, buffer).map_err(|error| error.to_string())
+}
+
+fn transform_16bit(image: DynamicImage, layout: Layout, transform: &Transform16BitExecutor) -> Result<DynamicImage, CmsError> {
+    let (width, height) = image.dimensions();
+    let pixels = match layout {
+        Layout::Gray => image.into_luma16().into_raw(),
+        Layout::GrayAlpha => image.into_luma_alpha16().into_raw(),
+        Layout::Rgb => image.into_rgb16().into_raw(),
+        _ => image.into_rgba16().into_raw()
+    };
+    let mut converted = vec![0; pixels.len()];
+    transform.transform(&pixels, &mut converted)?;
+    let converted = match layout {
+        Layout::Gray => ImageBuffer::from_raw(width, height, converted).map(DynamicImage::ImageLuma16),
+        Layout::GrayAlpha => ImageBuffer::from_raw(width, height, converted).map(DynamicImage::ImageLumaA16),
+        Layout::Rgb => ImageBuffer::from_raw(width, height, converted).map(DynamicImage::ImageRgb16),
+        _ => ImageBuffer::from_raw(width, height, converted).map(DynamicImage::ImageRgba16)
+    };
+    converted.ok_or(CmsError::LaneSizeMismatch)
+}
+
+fn with_color_type_of(image: DynamicImage, original: &DynamicImage) -> DynamicImage {
+    match original {
+        DynamicImage::ImageLuma8(_) => DynamicImage::ImageLuma8(image.into_luma8()),
+        DynamicImage::ImageLumaA8(_) => DynamicImage::ImageLumaA8(image.into_luma_alpha8()),
+        DynamicImage::ImageRgb8(_) => DynamicImage::ImageRgb8(image.into_rgb8()),
+        DynamicImage::ImageRgba8(_) => DynamicImage::ImageRgba8(image.into_rgba8()),
+        DynamicImage::ImageLuma16(_) => DynamicImage::ImageLuma16(image.into_luma16()),
+        DynamicImage::ImageLumaA16(_) => DynamicImage::ImageLumaA16(image.into_luma_alpha16()),
+        DynamicImage::ImageRgb16(_) => DynamicImage::ImageRgb16(image.into_rgb16()),
+        DynamicImage::ImageRgb32F(_) => DynamicImage::ImageRgb32F(image.into_rgb32f()),
+        DynamicImage::ImageRgba32F(_) => DynamicImage::ImageRgba32F(image.into_rgba32f()),
+        _ => DynamicImage::ImageRgba16(image.into_rgba16())
+    }
+}
+
+/// `None` when the pixels are left as decoded.
+fn target_profile(profile: &OutputProfile) -> Option<Cow<'_, ColorProfile>> {
+    match profile {
+        OutputProfile::Source => None,
+        OutputProfile::File(file) => Some(Cow::Borrowed(&file.profile)),
+        profile => Some(Cow::Owned(built_in(profile)))
+    }
+}
+
+fn built_in(profile: &OutputProfile) -> ColorProfile {
+    match profile {
+        OutputProfile::DisplayP3 => ColorProfile::new_display_p3(),
+        OutputProfile::AdobeRgb => ColorProfile::new_adobe_rgb(),
+        OutputProfile::ProPhotoRgb => ColorProfile::new_pro_photo_rgb(),
+        _ => ColorProfile::new_srgb()
+    }
+}
+
+fn convert(image: DynamicImage, source_icc: Option<&[u8]>, target: &ColorProfile) -> Result<DynamicImage, String> {
+    let source = match source_icc {
+        Some(icc) => ColorProfile::new_from_slice(icc).map_err(|error| format!("invalid embedded color profile: {}", error))?,
+        None => ColorProfile::new_srgb()
+    };
+    let gray = source.color_space == DataColorSpace::Gray;
+    if !matches!(source.color_space, DataColorSpace::Rgb | DataColorSpace::Gray) || (gray && image.color().channel_count() > 2) {
+        debug!("Ignoring a {:?} color profile", source.color_space);
+        return Ok(image);
+    }
+    transform(image, &source, target, gray).map_err(|error| format!("unable to convert colors: {}", error))
+}
+
+/// Runs the transform at the bit depth of the image; gray sources come out as RGB of the target profile.
+fn transform(image: DynamicImage, source: &ColorProfile, target: &ColorProfile, gray: bool) -> Result<DynamicImage, CmsError> {
+    let (width, height) = image.dimensions();
+    let alpha = image.color().has_alpha();
+    let (source_layout, target_layout) = match (gray, alpha) {
+        (true, false) => (Layout::Gray, Layout::Rgb),
+        (true, true) => (Layout::GrayAlpha, Layout::Rgba),
+        (false, false) => (Layout::Rgb, Layout::Rgb),
+        (false, true) => (Layout::Rgba, Layout::Rgba)
+    };
+    let options = TransformOptions::default();
+    let length = width as usize * height as usize * target_layout.channels();
+    let bytes_per_sample = image.color().bytes_per_pixel() / image.color().channel_count();
+    let converted = match bytes_per_sample {
+        1 => {
+            let pixels = match (gray, alpha) {
+                (true, false) => image.into_luma8().into_raw(),
+                (true, true) => image.into_luma_alpha8().into_raw(),
+                (false, false) => image.into_rgb8().into_raw(),
+                (false, true) => image.into_rgba8().into_raw()
+            };
+            let mut converted = vec![0; length];
+            source.create_transform_8bit(source_layout, target, target_layout, options)?.transform(&pixels, &mut converted)?;
+            match alpha {
+                false => ImageBuffer::from_raw(width, height, converted).map(DynamicImage::ImageRgb8),
+                true => ImageBuffer::from_raw(width, height, converted).map(DynamicImage::ImageRgba8)
+            }
+        }
+        2 => {
+            let pixels = match (gray, alpha) {
+                (true, false) => image.into_luma16().into_raw(),
+                (true, true) => image.into_luma_alpha16().into_raw(),
+                (false, false) => image.into_rgb16().into_raw(),
+                (false, true) => image.into_rgba16().into_raw()
+            };
+            let mut converted = vec![0; length];
+            source.create_transform_16bit(source_layout, target, target_layout, options)?.transform(&pixels, &mut converted)?;
+            match alpha {
+                false => ImageBuffer::from_raw(width, height, converted).map(DynamicImage::ImageRgb16),
+                true => ImageBuffer::from_raw(width, height, converted).map(DynamicImage::ImageRgba16)
+            }
+        }
+        _ => {
+            // there are no gray float images, so the source is RGB here
+            let pixels = match alpha {
+                false => image.into_rgb32f().into_raw(),
+                true => image.into_rgba32f().into_raw()
+            };
+            let mut converted = vec![0.0; length];
+            source.create_transform_f32(source_layout, target, target_layout, options)?.transform(&pixels, &mut converted)?;
+            match alpha {
+                false => ImageBuffer::from_raw(width, height, converted).map(DynamicImage::ImageRgb32F),
+                true => ImageBuffer::from_raw(width, height, converted).map(DynamicImage::ImageRgba32F)
+            }
+        }
+    };
+    converted.ok_or(CmsError::LaneSizeMismatch)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::imageops::FilterType;
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn source_profiles_are_embedded_only_on_pixels_they_describe() {
+        let args = Args { color_profile: OutputProfile::Source, embed_profile: EmbedProfile::Always, ..Args::default() };
+        let rgb = ColorProfile::new_srgb().encode().unwrap();
+        let gray = ColorProfile::new_gray_with_gamma(2.2).encode().unwrap();
+        assert_eq!(output_profile(Some(&rgb), ColorType::Rgb8, &args), Ok(Some(rgb.clone())));
+        assert_eq!(output_profile(Some(&rgb), ColorType::Rgba16, &args), Ok(Some(rgb.clone())));
+        assert_eq!(output_profile(Some(&gray), ColorType::L8, &args), Ok(Some(gray.clone())));
+        assert_eq!(output_profile(Some(&gray), ColorType::La16, &args), Ok(Some(gray.clone())));
+        // a gray source flattened to RGB, and an RGB profile on gray pixels
+        assert_eq!(output_profile(Some(&gray), ColorType::Rgb8, &args), Ok(None));
+        assert_eq!(output_profile(Some(&rgb), ColorType::L8, &args), Ok(None));
+        assert_eq!(output_profile(Some(b"not a profile"), ColorType::Rgb8, &args), Ok(None));
+        assert_eq!(output_profile(None, ColorType::Rgb8, &args), Ok(None));
+    }
+
+    #[test]
+    fn linear_resizing_averages_light_rather_than_encoded_values() {
+        // one pixel wide black and white stripes average to half the light, which sRGB encodes as 188 rather than 128
+        let stripes = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 8, |x, _| Rgb([if x % 2 == 0 { 0 } else { 255 }; 3])));
+        let halve = |img: &DynamicImage| img.resize_exact(32, 4, FilterType::Triangle);
+        assert_eq!(halve(&stripes).to_rgb8().get_pixel(16, 2)[0], 128);
+        let linear = resize_linear(&stripes, &ColorProfile::new_srgb(), halve).unwrap();
+        assert_eq!(linear.color(), ColorType::Rgb8);
+        assert!((186..=190).contains(&linear.to_rgb8().get_pixel(16, 2)[0]));
+
+        // gray pixels with a gray profile stay gray, with their alpha
+        let gray = DynamicImage::ImageLumaA8(stripes.to_luma_alpha8());
+        let linear = resize_linear(&gray, &ColorProfile::new_gray_with_gamma(2.2), halve).unwrap();
+        assert_eq!(linear.color(), ColorType::La8);
+        assert!((184..=188).contains(&linear.to_luma_alpha8().get_pixel(16, 2)[0]));
+
+        let mut without_curves = ColorProfile::new_srgb();
+        without_curves.red_trc = None;
+        assert!(resize_linear(&stripes, &without_curves, halve).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use fraction::Fraction;
+use tracing::{error, info};
+
+use crate::imports::directory_to_files;
+use crate::logging;
+use crate::metadata;
+use crate::metadata::Metadata;
+use crate::process::{estimate_file_memory, output_file_name, process_image_to, record_image};
+use crate::scheduler::{for_each_bounded, Limits, Scheduler};
+use crate::state::{settings_hash, Journal, State};
+use crate::structs::Args;
+
+pub fn process(args: &Args) {
+    let batch_size = args.batch_size;
+    let input = args.input.as_str();
+    println!(":::::Settings:::::\naspect ratio: {}\nimage type to decode: {}\nbatch size: {}\ninput directory: {}\noutput directory: {}\nmax image width: {}\nJPEG quality: {}\n", args.aspect_ratio, args.decode, args.batch_size, args.input, args.output, args.max_width, args.quality);
+
+    let extensions: Vec<&str> = args.decode.split("|").collect();
+    let filtered_files = directory_to_files(input, &extensions);
+    let mut state = State::load_for(args);
+    let (filtered_files, skipped) = state.retain_changed(filtered_files, args);
+    let settings_hash = settings_hash(args);
+    let state = Mutex::new(state);
+    let journal = match Journal::start(args) {
+        Ok(journal) => journal,
+        Err(error) => {
+            error!("Unable to start the journal in the output directory '{}': {}", args.output, error);
+            return;
+        }
+    };
+    let count = filtered_files.len();
+    if skipped > 0 {
+        info!("Skipping {} unchanged files (use --force to reprocess).", skipped);
+    }
+    match args.max_memory {
+        Some(max_memory) => println!("Processing {} files, up to {} at a time within {} of memory.", count, batch_size, max_memory),
+        None => println!("Processing {} files, up to {} at a time.", count, batch_size)
+    }
+
+    let progress_bar = logging::progress_bar(count as u64);
+    let scheduler = Scheduler::new(Limits::from_args(args));
+    for_each_bounded(&filtered_files, &scheduler, |file| estimate_file_memory(file, args, &scheduler), |file| {
+        record_image(file, args, &settings_hash, &state, &journal, &scheduler);
+        progress_bar.inc(1);
+    });
+    progress_bar.finish();
+    match state.into_inner().unwrap().save(&args.output) {
+        Ok(_) => { let _result = journal.finish(&args.output); }
+        Err(error) => error!("Unable to save processing state: {}", error)
+    }
+    println!("\nComplete.");
+}
+
+pub fn inspect(file: &Path) {
+    let reader = match image::io::Reader::open(file).and_then(|reader| reader.with_guessed_format()) {
+        Ok(reader) => reader,
+        Err(error) => {
+            error!(file = %file.display(), "Unable to read: {}", error);
+            return;
+        }
+    };
+    let format = reader.format();
+    println!(":::::Image:::::\nfile: {}", file.display());
+    match format {
+        Some(format) => println!("format: {:?}", format),
+        None => println!("format: unknown")
+    }
+    match reader.into_dimensions() {
+        Ok((width, height)) => println!("dimensions: {}x{}\naspect ratio: {}", width, height, Fraction::from(width) / Fraction::from(height)),
+        Err(error) => println!("dimensions: unavailable ({})", error)
+    }
+
+    println!("\n:::::Metadata:::::\nbackend: {}", metadata::BACKEND);
+    match Metadata::new_from_path(file) {
+        Ok(meta) => {
+            for tag in meta.tags().iter().map(String::as_str).chain(meta.opaque_families()) {
+                let value = meta.tag_string(tag).unwrap_or_default();
+                println!("{}: {}", tag, value);
+            }
+        }
+        Err(error) => println!("unavailable ({})", error)
+    }
+}
+
+/// Renders one image with the current settings through the same steps as `process`, color profile, metadata
+/// and stamp included, and writes it to `to` instead of the output directory.
+pub fn preview(file: &Path, to: Option<PathBuf>, args: &Args) {
+    let file_name = file.file_name().and_then(OsStr::to_str).unwrap_or_default();
+    let existing_extension = file.extension().and_then(OsStr::to_str).unwrap_or_default().to_lowercase();
+    let encode = args.encode.to_lowercase();
+    let new_extension = if encode == "original" { existing_extension.as_str() } else { encode.as_str() };
+    let target = to.unwrap_or_else(|| Path::new(&args.output).join(format!("preview-{}", output_file_name(file_name, new_extension))));
+    let scheduler = Scheduler::new(Limits::from_args(args));
+    if process_image_to(file, args, &settings_hash(args), Some(&target), &scheduler).is_none() {
+        return;
+    }
+    let size = fs::metadata(&target).map(|metadata| metadata.len()).unwrap_or_default();
+    match image::image_dimensions(&target) {
+        Ok((width, height)) => println!("{} -> {} ({}x{}, {} bytes)", file_name, target.display(), width, height, size),
+        Err(_) => println!("{} -> {} ({} bytes)", file_name, target.display(), size)
+    }
+}
@@ -0,0 +1,412 @@
+#[cfg(feature = "ui")]
+use std::collections::BTreeSet;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+#[cfg(feature = "ui")]
+use std::sync::OnceLock;
+
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, ValueEnum};
+use fraction::Fraction;
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+
+use crate::structs::{Args, Background, ByteSize, EmbedProfile, LogFormat, MetadataPolicy, OutputProfile, Resizer, Stamp};
+
+const DEFAULT_CONFIG_FILES: [&str; 3] = ["blipb.toml", "blipb.yaml", "blipb.yml"];
+
+/// The command line `parse_args` was given, so the UI can tell typed options from the ones it may restore.
+#[cfg(feature = "ui")]
+static COMMAND_LINE: OnceLock<ArgMatches> = OnceLock::new();
+
+/// Names of the options the configuration file or preset read by `parse_args` set, which the UI doesn't restore either.
+#[cfg(feature = "ui")]
+static CONFIGURED: OnceLock<BTreeSet<String>> = OnceLock::new();
+
+/// Settings that can be given in a configuration file or preset. Every field mirrors the `Args` option of the same name.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Settings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aspect_ratio: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batch_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color_profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embed_profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fast_decode: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub force: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub force_8bit: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub io_threads: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub linear_resize: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_memory: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quiet: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resizer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resume: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stamp: Option<Stamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strip_alpha: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strip_gps: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threads: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_file: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trim: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trim_padding: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trim_tolerance: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verbose: Option<u8>,
+}
+
+#[cfg(feature = "ui")]
+impl Settings {
+    /// The pipeline options of `args`, leaving out per-run switches such as `--force`, `--resume`, `--trace-file` and logging.
+    pub fn from_args(args: &Args) -> Settings {
+        Settings {
+            aspect_ratio: Some(args.aspect_ratio.to_string()),
+            background: Some(args.background.to_string()),
+            batch_size: Some(args.batch_size),
+            color_profile: Some(args.color_profile.to_string()),
+            decode: Some(args.decode.clone()),
+            embed_profile: Some(value_name(args.embed_profile)),
+            encode: Some(args.encode.clone()),
+            fast_decode: Some(args.fast_decode),
+            force_8bit: Some(args.force_8bit),
+            input: Some(args.input.clone()),
+            io_threads: args.io_threads,
+            linear_resize: Some(args.linear_resize),
+            max_memory: args.max_memory.map(|size| size.to_string()),
+            max_width: Some(args.max_width),
+            metadata: Some(args.metadata.to_string()),
+            output: Some(args.output.clone()),
+            quality: Some(args.quality),
+            resizer: Some(value_name(args.resizer)),
+            stamp: args.stamp.clone(),
+            strip_alpha: Some(args.strip_alpha),
+            strip_gps: Some(args.strip_gps),
+            threads: args.threads,
+            trim: Some(args.trim),
+            trim_padding: Some(args.trim_padding),
+            trim_tolerance: Some(args.trim_tolerance),
+            ..Default::default()
+        }
+    }
+
+    /// The names of the options this sets, as written in the file, e.g. `max-width`.
+    fn keys(&self) -> Vec<String> {
+        match toml::Value::try_from(self) {
+            Ok(toml::Value::Table(table)) => table.keys().cloned().collect(),
+            _ => Vec::new()
+        }
+    }
+}
+
+/// A `blipb.toml` (or YAML) file: top-level settings followed by named `[preset.<name>]` tables.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct ConfigFile {
+    #[serde(flatten)]
+    pub settings: Settings,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub preset: HashMap<String, Settings>,
+    // whatever is left of the top level; serde can't deny unknown fields next to a flattened struct
+    #[serde(flatten, skip_serializing)]
+    unknown: BTreeMap<String, IgnoredAny>,
+}
+
+#[cfg(feature = "ui")]
+impl ConfigFile {
+    /// A file with only top-level settings and no presets.
+    pub fn new(settings: Settings) -> ConfigFile {
+        ConfigFile { settings, ..Default::default() }
+    }
+}
+
+/// Parses the command line and fills in every option that was not given on it from the configuration file,
+/// preset values taking precedence over top-level ones.
+pub fn parse_args() -> Args {
+    let matches = Args::command().get_matches();
+    #[cfg(feature = "ui")]
+    let _result = COMMAND_LINE.set(matches.clone());
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+    let path = match args.config.clone().or_else(default_config_path) {
+        Some(path) => path,
+        None => {
+            if let Some(preset) = &args.preset {
+                exit_with_error(format!("preset '{}' requested but no configuration file was found", preset));
+            }
+            return args;
+        }
+    };
+    let config = load(&path).unwrap_or_else(|error| exit_with_error(error));
+    let mut layers = vec![&config.settings];
+    if let Some(preset) = &args.preset {
+        match config.preset.get(preset) {
+            Some(settings) => layers.push(settings),
+            None => exit_with_error(format!("preset '{}' not found in {}", preset, path.display()))
+        }
+    }
+    #[cfg(feature = "ui")]
+    let _result = CONFIGURED.set(layers.iter().flat_map(|settings| settings.keys()).collect());
+    for settings in layers {
+        if let Err(error) = apply(&mut args, settings, &matches) {
+            exit_with_error(format!("{}: {}", path.display(), error));
+        }
+    }
+    args
+}
+
+pub fn load(path: &Path) -> Result<ConfigFile, String> {
+    let contents = fs::read_to_string(path).map_err(|error| format!("unable to read {}: {}", path.display(), error))?;
+    let parsed: Result<ConfigFile, String> = if is_yaml(path) {
+        serde_yaml::from_str(&contents).map_err(|error| error.to_string())
+    } else {
+        toml::from_str(&contents).map_err(|error| error.to_string())
+    };
+    match parsed {
+        Ok(config) => match config.unknown.keys().next() {
+            Some(key) => Err(format!("unable to parse {}: unknown setting '{}'", path.display(), key)),
+            None => Ok(config)
+        },
+        Err(error) => Err(format!("unable to parse {}: {}", path.display(), error))
+    }
+}
+
+#[cfg(feature = "ui")]
+pub fn save(path: &Path, config: &ConfigFile) -> Result<(), String> {
+    let contents = if is_yaml(path) {
+        serde_yaml::to_string(config).map_err(|error| error.to_string())?
+    } else {
+        toml::to_string_pretty(config).map_err(|error| error.to_string())?
+    };
+    fs::write(path, contents).map_err(|error| format!("unable to write {}: {}", path.display(), error))
+}
+
+fn apply(args: &mut Args, settings: &Settings, matches: &ArgMatches) -> Result<(), String> {
+    let from_file = |id: &str| !is_explicit(matches, id);
+    macro_rules! apply {
+        ($field:ident) => {
+            if let Some(value) = &settings.$field {
+                if from_file(stringify!($field)) {
+                    args.$field = value.clone();
+                }
+            }
+        };
+        (optional $field:ident) => {
+            if let Some(value) = &settings.$field {
+                if from_file(stringify!($field)) {
+                    args.$field = Some(value.clone());
+                }
+            }
+        };
+    }
+    if let Some(value) = &settings.aspect_ratio {
+        if from_file("aspect_ratio") {
+            args.aspect_ratio = Fraction::from_str(value).map_err(|_| format!("invalid aspect-ratio '{}'", value))?;
+        }
+    }
+    if let Some(value) = &settings.background {
+        if from_file("background") {
+            args.background = Background::from_str(value).map_err(|error| format!("invalid background: {}", error))?;
+        }
+    }
+    apply!(batch_size);
+    if let Some(value) = &settings.color_profile {
+        if from_file("color_profile") {
+            args.color_profile = OutputProfile::from_str(value).map_err(|error| format!("invalid color-profile: {}", error))?;
+        }
+    }
+    apply!(decode);
+    if let Some(value) = &settings.embed_profile {
+        if from_file("embed_profile") {
+            args.embed_profile = EmbedProfile::from_str(value, true).map_err(|_| format!("invalid embed-profile '{}'", value))?;
+        }
+    }
+    apply!(encode);
+    apply!(fast_decode);
+    apply!(force);
+    apply!(force_8bit);
+    apply!(input);
+    apply!(optional io_threads);
+    apply!(linear_resize);
+    if let Some(value) = &settings.log_format {
+        if from_file("log_format") {
+            args.log_format = LogFormat::from_str(value, true).map_err(|_| format!("invalid log-format '{}'", value))?;
+        }
+    }
+    if let Some(value) = &settings.max_memory {
+        if from_file("max_memory") {
+            args.max_memory = Some(ByteSize::from_str(value).map_err(|error| format!("invalid max-memory: {}", error))?);
+        }
+    }
+    apply!(max_width);
+    if let Some(value) = &settings.metadata {
+        if from_file("metadata") {
+            args.metadata = MetadataPolicy::from_str(value).map_err(|error| format!("invalid metadata: {}", error))?;
+        }
+    }
+    apply!(output);
+    apply!(quality);
+    apply!(quiet);
+    if let Some(value) = &settings.resizer {
+        if from_file("resizer") {
+            args.resizer = Resizer::from_str(value, true).map_err(|_| format!("invalid resizer '{}'", value))?;
+        }
+    }
+    apply!(resume);
+    // there is no command line flag, so a preset's stamp simply replaces the top-level one
+    if let Some(stamp) = &settings.stamp {
+        validate_stamp(stamp)?;
+        args.stamp = Some(stamp.clone());
+    }
+    apply!(strip_alpha);
+    apply!(strip_gps);
+    apply!(optional threads);
+    apply!(optional trace_file);
+    apply!(trim);
+    apply!(trim_padding);
+    apply!(trim_tolerance);
+    apply!(verbose);
+    Ok(())
+}
+
+// the pattern is already compiled when the file is parsed
+fn validate_stamp(stamp: &Stamp) -> Result<(), String> {
+    match stamp.tags.keys().find(|tag| !matches!(tag.split('.').next(), Some("Exif" | "Iptc" | "Xmp"))) {
+        Some(tag) => Err(format!("stamp tag '{}' is not an Exif, Iptc or Xmp tag", tag)),
+        None => Ok(())
+    }
+}
+
+/// True when the option was typed on the command line given to `parse_args`, or set by the configuration file or preset it read.
+#[cfg(feature = "ui")]
+pub fn given_explicitly(id: &str) -> bool {
+    COMMAND_LINE.get().is_some_and(|matches| is_explicit(matches, id))
+        || CONFIGURED.get().is_some_and(|keys| keys.contains(&id.replace('_', "-")))
+}
+
+/// True when the option was typed on the command line, either before or after the subcommand.
+fn is_explicit(matches: &ArgMatches, id: &str) -> bool {
+    let mut matches = Some(matches);
+    while let Some(current) = matches {
+        if current.value_source(id) == Some(ValueSource::CommandLine) {
+            return true;
+        }
+        matches = current.subcommand().map(|(_, sub_matches)| sub_matches);
+    }
+    false
+}
+
+#[cfg(feature = "ui")]
+fn value_name<T: ValueEnum>(value: T) -> String {
+    value.to_possible_value().unwrap().get_name().to_string()
+}
+
+fn default_config_path() -> Option<PathBuf> {
+    DEFAULT_CONFIG_FILES.iter().map(PathBuf::from).find(|path| path.is_file())
+}
+
+fn is_yaml(path: &Path) -> bool {
+    matches!(path.extension().and_then(|extension| extension.to_str()), Some("yaml" | "yml"))
+}
+
+fn exit_with_error(message: String) -> ! {
+    Args::command().bin_name("blipb").error(ErrorKind::InvalidValue, message).exit()
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn load_str(contents: &str, extension: &str) -> Result<ConfigFile, String> {
+        let path = std::env::temp_dir().join(format!("blipb-test-{}.{}", Uuid::new_v4(), extension));
+        fs::write(&path, contents).unwrap();
+        let config = load(&path);
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    fn layered(config: &ConfigFile, preset: &str, command_line: &[&str]) -> Result<Args, String> {
+        let matches = Args::command().get_matches_from(command_line);
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        apply(&mut args, &config.settings, &matches)?;
+        apply(&mut args, &config.preset[preset], &matches)?;
+        Ok(args)
+    }
+
+    #[test]
+    fn command_line_beats_preset_beats_top_level() {
+        let config = load_str("encode = \"png\"\nmax-width = 2000\nquality = 80\n\n[preset.web]\nmax-width = 1000\nquality = 60\n", "toml").unwrap();
+        let args = layered(&config, "web", &["blipb", "process", "--quality", "95"]).unwrap();
+        assert_eq!(args.quality, 95);
+        assert_eq!(args.max_width, 1000);
+        assert_eq!(args.encode, "png");
+        assert_eq!(args.aspect_ratio, Fraction::new(5u64, 7u64));
+    }
+
+    #[test]
+    fn yaml_files_are_read_like_toml() {
+        let config = load_str("max-memory: 512M\npreset:\n  print:\n    metadata: copyright-only\n", "yaml").unwrap();
+        let args = layered(&config, "print", &["blipb"]).unwrap();
+        assert_eq!(args.max_memory, Some(ByteSize(512 << 20)));
+        assert_eq!(args.metadata, MetadataPolicy::CopyrightOnly);
+    }
+
+    #[test]
+    fn invalid_values_are_reported() {
+        let config = load_str("aspect-ratio = \"wide\"\n[preset.web]\n", "toml").unwrap();
+        assert!(layered(&config, "web", &["blipb"]).is_err());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(load_str("max_width = 4000\n", "toml").unwrap_err().contains("max_width"));
+        assert!(load_str("[presets.web]\nmax-width = 4000\n", "toml").unwrap_err().contains("presets"));
+        assert!(load_str("[preset.web]\nmax_width = 4000\n", "toml").unwrap_err().contains("max_width"));
+        assert!(load_str("[stamp]\npatern = \"^x\"\n", "toml").unwrap_err().contains("patern"));
+        assert!(load_str("max_width: 4000\n", "yaml").unwrap_err().contains("max_width"));
+    }
+
+    #[test]
+    fn invalid_stamp_patterns_are_rejected_on_load() {
+        assert!(load_str("[stamp]\npattern = \"(\"\n", "toml").unwrap_err().contains("invalid stamp pattern"));
+    }
+}
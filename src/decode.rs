@@ -0,0 +1,189 @@
+#[cfg(feature = "ui")]
+use std::fs;
+#[cfg(feature = "ui")]
+use std::path::Path;
+
+use fraction::{Fraction, ToPrimitive};
+use image::error::{DecodingError, ImageFormatHint};
+use image::{DynamicImage, ImageBuffer, ImageError, ImageFormat, ImageResult};
+use jpeg_decoder::PixelFormat;
+
+#[cfg(feature = "ui")]
+use crate::color::{convert_to_srgb, icc_profile};
+use crate::metadata::read_orientation;
+use crate::structs::Args;
+
+/// Decodes an image from its file contents. With `--fast-decode`, JPEGs whose output needs at most half
+/// of the source resolution are decoded with the scaled IDCT (1/2, 1/4 or 1/8), never below the output size,
+/// and `resize_image` then finishes the reduction with the usual CatmullRom resample. With `--trim` the content may
+/// be much smaller than the crop computed here, so images are decoded at full size.
+/// The image is returned upright, with its EXIF orientation applied, so it is cropped the way it is viewed.
+pub fn decode_image(bytes: &[u8], args: &Args) -> ImageResult<DynamicImage> {
+    let orientation = read_orientation(bytes).unwrap_or(1);
+    if args.fast_decode && !args.trim && image::guess_format(bytes).ok() == Some(ImageFormat::Jpeg) {
+        if let Some(image) = decode_jpeg_scaled(bytes, args, swaps_axes(orientation))? {
+            return Ok(apply_orientation(image, orientation));
+        }
+    }
+    image::load_from_memory(bytes).map(|image| apply_orientation(image, orientation))
+}
+
+/// Opens an image for the UI, upright like the processed output and converted to sRGB for the screen.
+#[cfg(feature = "ui")]
+pub fn open_upright(path: &Path) -> ImageResult<DynamicImage> {
+    let bytes = fs::read(path)?;
+    let image = image::load_from_memory(&bytes).map(|image| apply_orientation(image, read_orientation(&bytes).unwrap_or(1)))?;
+    convert_to_srgb(image, icc_profile(&bytes).as_deref()).map_err(|error| ImageError::Decoding(DecodingError::new(ImageFormatHint::Unknown, error)))
+}
+
+/// Turns the stored pixels into the orientation a viewer would show, for the EXIF orientation values 1 to 8.
+fn apply_orientation(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate90().flipv(),
+        8 => image.rotate270(),
+        _ => image
+    }
+}
+
+fn swaps_axes(orientation: u16) -> bool {
+    (5..=8).contains(&orientation)
+}
+
+/// `None` for the pixel formats left to the regular decoder (16-bit greyscale).
+/// `rotated` means the image is turned by 90 degrees after decoding, so the crop is measured on swapped sides.
+fn decode_jpeg_scaled(bytes: &[u8], args: &Args, rotated: bool) -> ImageResult<Option<DynamicImage>> {
+    let mut decoder = jpeg_decoder::Decoder::new(bytes);
+    decoder.read_info().map_err(jpeg_error)?;
+    let info = decoder.info().unwrap();
+    if info.pixel_format == PixelFormat::L16 {
+        return Ok(None);
+    }
+    let (width, height) = (info.width as u32, info.height as u32);
+    let (crop_width, _) = if rotated {
+        cropped_size(height, width, args.aspect_ratio)
+    } else {
+        cropped_size(width, height, args.aspect_ratio)
+    };
+    let target_width = crop_width.min(args.max_width);
+    if target_width > 0 && target_width * 2 <= crop_width {
+        let scale = target_width as f64 / crop_width as f64;
+        let requested_width = (width as f64 * scale).ceil() as u16;
+        let requested_height = (height as f64 * scale).ceil() as u16;
+        decoder.scale(requested_width, requested_height).map_err(jpeg_error)?;
+    }
+
+    let pixels = decoder.decode().map_err(jpeg_error)?;
+    let info = decoder.info().unwrap();
+    let (width, height) = (info.width as u32, info.height as u32);
+    let image = match info.pixel_format {
+        PixelFormat::L8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+        PixelFormat::RGB24 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+        PixelFormat::CMYK32 => ImageBuffer::from_raw(width, height, cmyk_to_rgb(&pixels)).map(DynamicImage::ImageRgb8),
+        PixelFormat::L16 => None
+    };
+    match image {
+        Some(image) => Ok(Some(image)),
+        None => Err(ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(ImageFormat::Jpeg), "decoded size does not match the header")))
+    }
+}
+
+/// Size of the center crop `crop_image` takes for the aspect ratio.
+fn cropped_size(width: u32, height: u32, aspect_ratio: Fraction) -> (u32, u32) {
+    let current_aspect = Fraction::from(width) / Fraction::from(height);
+    if aspect_ratio < current_aspect {
+        ((height as f64 * aspect_ratio.to_f64().unwrap()) as u32, height)
+    } else {
+        (width, (width as f64 / aspect_ratio.to_f64().unwrap()) as u32)
+    }
+}
+
+// same conversion the `image` JPEG decoder applies to Adobe CMYK
+fn cmyk_to_rgb(input: &[u8]) -> Vec<u8> {
+    input
+        .chunks_exact(4)
+        .flat_map(|pixel| {
+            let k = 255 - u16::from(pixel[3]);
+            [0, 1, 2].map(|channel| ((k * (255 - u16::from(pixel[channel]))) / 255) as u8)
+        })
+        .collect()
+}
+
+fn jpeg_error(error: jpeg_decoder::Error) -> ImageError {
+    ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(ImageFormat::Jpeg), error))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::codecs::jpeg::JpegEncoder;
+    use image::imageops::FilterType;
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    fn gradient_jpeg(width: u32, height: u32) -> Vec<u8> {
+        let img = RgbImage::from_fn(width, height, |x, y| Rgb([(x * 255 / width) as u8, (y * 255 / height) as u8, 128]));
+        let mut bytes = Vec::new();
+        img.write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, 90)).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn orientations_turn_the_stored_pixels_upright() {
+        // every pixel holds its stored position, 3 wide and 2 high, so the upright image shows where each one came from
+        let stored = DynamicImage::ImageRgb8(RgbImage::from_fn(3, 2, |x, y| Rgb([x as u8, y as u8, 0])));
+        let source = |orientation: u16, x: u32, y: u32| match orientation {
+            2 => (2 - x, y),
+            3 => (2 - x, 1 - y),
+            4 => (x, 1 - y),
+            5 => (y, x),
+            6 => (y, 1 - x),
+            7 => (2 - y, 1 - x),
+            8 => (2 - y, x),
+            _ => (x, y)
+        };
+        for orientation in 1..=8 {
+            let upright = apply_orientation(stored.clone(), orientation).into_rgb8();
+            let size = if swaps_axes(orientation) { (2, 3) } else { (3, 2) };
+            assert_eq!(upright.dimensions(), size, "orientation {}", orientation);
+            for (x, y, pixel) in upright.enumerate_pixels() {
+                assert_eq!((pixel[0] as u32, pixel[1] as u32), source(orientation, x, y), "orientation {} at {},{}", orientation, x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn large_reductions_decode_at_the_smallest_scale_that_covers_the_output() {
+        let bytes = gradient_jpeg(1600, 1200);
+        let args = Args { aspect_ratio: Fraction::new(4u64, 3u64), max_width: 300, fast_decode: true, ..Args::default() };
+        // 1/8 would leave 200 pixels across, fewer than the 300 of the output
+        let image = decode_jpeg_scaled(&bytes, &args, false).unwrap().unwrap();
+        assert_eq!((image.width(), image.height()), (400, 300));
+        let small_reduction = Args { max_width: 1000, ..args.clone() };
+        assert_eq!(decode_jpeg_scaled(&bytes, &small_reduction, false).unwrap().unwrap().width(), 1600);
+    }
+
+    #[test]
+    fn the_crop_is_measured_on_the_upright_image() {
+        let bytes = gradient_jpeg(1600, 1200);
+        let args = Args { aspect_ratio: Fraction::new(3u64, 4u64), max_width: 300, fast_decode: true, ..Args::default() };
+        // stored sideways the 3/4 crop is 900 wide and needs 1/2, upright it is the whole 1200 and 1/4 is enough
+        assert_eq!(decode_jpeg_scaled(&bytes, &args, false).unwrap().unwrap().width(), 800);
+        assert_eq!(decode_jpeg_scaled(&bytes, &args, true).unwrap().unwrap().width(), 400);
+    }
+
+    #[test]
+    fn scaled_decoding_resizes_to_nearly_the_same_pixels() {
+        let bytes = gradient_jpeg(1600, 1200);
+        let args = Args { aspect_ratio: Fraction::new(4u64, 3u64), max_width: 300, fast_decode: true, ..Args::default() };
+        let fast = decode_image(&bytes, &args).unwrap().resize_exact(300, 225, FilterType::CatmullRom).into_rgb8();
+        let full = image::load_from_memory(&bytes).unwrap().resize_exact(300, 225, FilterType::CatmullRom).into_rgb8();
+        let total: u64 = fast.as_raw().iter().zip(full.as_raw()).map(|(a, b)| a.abs_diff(*b) as u64).sum();
+        let mean = total as f64 / fast.as_raw().len() as f64;
+        assert!(mean < 1.0, "mean difference of {} levels", mean);
+    }
+}
@@ -1,18 +1,26 @@
 use std::fs;
 use std::fs::DirEntry;
 use std::io::Result;
+use std::path::Path;
 
 pub fn directory_to_files(path: &str, extensions: &Vec<&str>) -> Vec<Result<DirEntry>> {
+    let _span = tracing::info_span!("scan", directory = path).entered();
     let paths = fs::read_dir(path).unwrap();
-    let filtered_files: Vec<_> = paths
+    paths
         .into_iter()
         .filter(|path| file_extension_filter(path, extensions))
-        .collect();
-    return filtered_files;
+        .collect()
 }
 
 pub fn file_extension_filter(path: &Result<DirEntry>, extensions: &Vec<&str>) -> bool {
     let file_name = path.as_ref().unwrap().file_name().into_string().unwrap();
     let file_extension = file_name.split(".").last().unwrap();
-    return extensions.contains(&file_extension);
+    extensions.contains(&file_extension)
+}
+
+pub fn path_extension_filter(path: &Path, extensions: &[&str]) -> bool {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => extensions.contains(&extension),
+        None => false
+    }
 }
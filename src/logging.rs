@@ -0,0 +1,80 @@
+use std::any::Any;
+use std::io;
+use std::io::{IsTerminal, Write};
+use std::sync::Mutex;
+
+use indicatif::ProgressBar;
+use tracing::error;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
+
+use crate::structs::{Args, LogFormat};
+use crate::trace;
+use crate::trace::BoxedLayer;
+
+/// The progress bar currently drawn on stderr, hidden while a log line is written so the two don't interleave.
+static PROGRESS: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// Sends log messages to stderr, keeping stdout for reports, at the level chosen with `-v`/`--quiet`
+/// and in the `--log-format`, and adds the `--trace-file` recorder when one is requested.
+/// Returns the trace guard, which has to live until the end of `main`.
+pub fn init(args: &Args) -> Option<Box<dyn Any>> {
+    let filter = Targets::new().with_target(env!("CARGO_CRATE_NAME"), level(args.verbose, args.quiet));
+    let log_layer: BoxedLayer = match args.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_writer(|| ProgressWriter)
+            .with_ansi(io::stderr().is_terminal())
+            .with_target(false)
+            .with_filter(filter)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_writer(|| ProgressWriter)
+            .with_filter(filter)
+            .boxed(),
+    };
+    let mut layers = vec![log_layer];
+    let (guard, trace_error) = match args.trace_file.as_deref().map(trace::layer) {
+        Some(Ok((trace_layer, guard))) => {
+            layers.push(trace_layer);
+            (Some(guard), None)
+        }
+        Some(Err(error)) => (None, Some(error)),
+        None => (None, None)
+    };
+    tracing_subscriber::registry().with(layers).init();
+    if let Some(error) = trace_error {
+        error!("{}", error);
+    }
+    guard
+}
+
+/// A progress bar for a run that log messages are written around.
+pub fn progress_bar(len: u64) -> ProgressBar {
+    let progress_bar = ProgressBar::new(len);
+    *PROGRESS.lock().unwrap() = Some(progress_bar.clone());
+    progress_bar
+}
+
+// info by default, each -v or --quiet moves one level
+fn level(verbose: u8, quiet: u8) -> LevelFilter {
+    const LEVELS: [LevelFilter; 6] = [LevelFilter::OFF, LevelFilter::ERROR, LevelFilter::WARN, LevelFilter::INFO, LevelFilter::DEBUG, LevelFilter::TRACE];
+    let index = (3 + verbose as i32 - quiet as i32).clamp(0, LEVELS.len() as i32 - 1);
+    LEVELS[index as usize]
+}
+
+struct ProgressWriter;
+
+impl Write for ProgressWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match PROGRESS.lock().unwrap().as_ref() {
+            Some(progress_bar) => progress_bar.suspend(|| io::stderr().write(buf)),
+            None => io::stderr().write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
@@ -1,46 +1,43 @@
-use clap::Parser;
-use indicatif::ProgressBar;
-use rayon::prelude::*;
+use std::time::Duration;
 
-use crate::imports::directory_to_files;
-use crate::process::process_image;
-use crate::structs::Args;
+use crate::structs::Command;
 
+mod background;
+mod bench;
+mod color;
+mod commands;
+mod config;
+mod decode;
 mod imports;
+mod logging;
+mod metadata;
 mod process;
+mod resize;
+mod scheduler;
+mod stages;
+mod state;
+#[cfg(feature = "ui")]
 mod ui;
 mod structs;
+mod trace;
+mod trim;
+mod watch;
 
 
 fn main() {
-    let args = Args::parse();
-    rexiv2::initialize().expect("Unable to initialize 'rexiv2'. Please check the readme.md for external requirements.");
-    if args.ui {
-        ui::run(args);
-    } else {
-        let batch_size = args.batch_size;
-        let input = args.input.as_str();
-        println!(":::::Settings:::::\naspect ratio: {}\nimage type to decode: {}\nbatch size: {}\ninput directory: {}\noutput directory: {}\nmax image width: {}\nJPEG quality: {}\n", args.aspect_ratio, args.decode, args.batch_size, args.input, args.output, args.max_width, args.quality);
-
-        let extensions: Vec<&str> = args.decode.split("|").collect();
-        let filtered_files = directory_to_files(&input, &extensions);
-        let count = filtered_files.iter().count();
-        let chunks = (count as f64 / batch_size as f64).ceil();
-        println!("Processing {} files in {} chunks.", count, chunks);
-
-        let progress_bar = ProgressBar::new(count as u64);
-        filtered_files
-            .chunks(batch_size)
-            .for_each(|filtered_files_of_files| {
-                filtered_files_of_files
-                    .par_iter()
-                    .for_each(|file| {
-                        progress_bar.inc(1);
-                        process_image(file, &args);
-                    })
-            });
-        progress_bar.finish();
-        println!("\nComplete.");
+    let args = config::parse_args();
+    let _trace = logging::init(&args);
+    if let Err(error) = metadata::initialize() {
+        panic!("Unable to initialize the '{}' metadata backend: {}. Please check the readme.md for external requirements.", metadata::BACKEND, error);
+    }
+    match args.command.clone() {
+        None | Some(Command::Process) => commands::process(&args),
+        Some(Command::Inspect { file }) => commands::inspect(&file),
+        Some(Command::Preview { file, to }) => commands::preview(&file, to, &args),
+        Some(Command::Watch { settle_ms }) => watch::run(&args, Duration::from_millis(settle_ms)),
+        #[cfg(feature = "ui")]
+        Some(Command::Ui) => ui::run(args),
+        Some(Command::Bench { sizes, formats, count, seed, keep }) => bench::run(&args, &sizes, &formats, count, seed, keep),
     }
 }
 
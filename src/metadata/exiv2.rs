@@ -0,0 +1,91 @@
+use std::path::Path;
+
+use rexiv2::Orientation;
+
+pub const BACKEND: &str = "exiv2";
+
+/// EXIF, IPTC and XMP of an image through gexiv2, which can read and write every tag exiv2 knows.
+pub struct Metadata {
+    meta: rexiv2::Metadata,
+}
+
+pub fn initialize() -> Result<(), String> {
+    rexiv2::initialize().map_err(|error| error.to_string())
+}
+
+impl Metadata {
+    pub fn new_from_path(path: &Path) -> Result<Metadata, String> {
+        rexiv2::Metadata::new_from_path(path).map(|meta| Metadata { meta }).map_err(|error| error.to_string())
+    }
+
+    pub fn new_from_buffer(bytes: &[u8]) -> Result<Metadata, String> {
+        rexiv2::Metadata::new_from_buffer(bytes).map(|meta| Metadata { meta }).map_err(|error| error.to_string())
+    }
+
+    pub fn tags(&self) -> Vec<String> {
+        let tags = [self.meta.get_exif_tags(), self.meta.get_iptc_tags(), self.meta.get_xmp_tags()];
+        tags.into_iter().flat_map(|tags| tags.unwrap_or_default()).collect()
+    }
+
+    /// Human readable value of the tag, as exiv2 interprets it.
+    pub fn tag_string(&self, tag: &str) -> Option<String> {
+        self.meta.get_tag_interpreted_string(tag).ok()
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.meta.has_tag(tag)
+    }
+
+    pub fn clear_tag(&mut self, tag: &str) {
+        self.meta.clear_tag(tag);
+    }
+
+    pub fn set_tag_string(&mut self, tag: &str, value: &str) -> Result<(), String> {
+        self.meta.set_tag_string(tag, value).map_err(|error| error.to_string())
+    }
+
+    pub fn set_tag_numeric(&mut self, tag: &str, value: u32) -> Result<(), String> {
+        self.meta.set_tag_numeric(tag, value as i32).map_err(|error| error.to_string())
+    }
+
+    pub fn set_tag_multiple_strings(&mut self, tag: &str, values: &[&str]) -> Result<(), String> {
+        self.meta.set_tag_multiple_strings(tag, values).map_err(|error| error.to_string())
+    }
+
+    pub fn orientation(&self) -> Option<u16> {
+        match self.meta.get_orientation() {
+            Orientation::Unspecified => None,
+            orientation => Some(orientation as u16)
+        }
+    }
+
+    /// Sets the EXIF and XMP orientation to normal.
+    pub fn reset_orientation(&mut self) {
+        self.meta.set_orientation(Orientation::Normal);
+    }
+
+    pub fn delete_gps_info(&mut self) {
+        self.meta.delete_gps_info();
+    }
+
+    pub fn has_thumbnail(&self) -> bool {
+        self.meta.get_thumbnail().is_some()
+    }
+
+    pub fn set_thumbnail(&mut self, jpeg: &[u8]) {
+        self.meta.set_thumbnail_from_buffer(jpeg);
+    }
+
+    pub fn erase_thumbnail(&mut self) {
+        self.meta.erase_thumbnail();
+    }
+
+    /// Families that can only be kept or dropped as a whole; exiv2 handles every tag on its own.
+    pub fn opaque_families(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> Result<(), String> {
+        self.meta.save_to_file(path).map_err(|error| error.to_string())
+    }
+}
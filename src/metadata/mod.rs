@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::Path;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::DynamicImage;
+use tracing::{debug, warn};
+
+use crate::structs::{Args, MetadataPolicy, Stamp, StampPattern, StampValue};
+
+#[cfg(feature = "exiv2")]
+mod exiv2;
+#[cfg(feature = "exiv2")]
+pub use exiv2::{initialize, Metadata, BACKEND};
+
+#[cfg(all(feature = "native", not(feature = "exiv2")))]
+mod native;
+#[cfg(all(feature = "native", not(feature = "exiv2")))]
+pub use native::{initialize, Metadata, BACKEND};
+
+#[cfg(not(any(feature = "exiv2", feature = "native")))]
+compile_error!("a metadata backend is required: enable the `exiv2` or the `native` feature");
+
+/// Tags kept by `--metadata copyright-only`: creator, copyright and credit lines in each family.
+const COPYRIGHT_TAGS: [&str; 9] = [
+    "Exif.Image.Artist",
+    "Exif.Image.Copyright",
+    "Iptc.Application2.Byline",
+    "Iptc.Application2.Copyright",
+    "Iptc.Application2.Credit",
+    "Xmp.dc.creator",
+    "Xmp.dc.rights",
+    "Xmp.photoshop.Credit",
+    "Xmp.xmpRights",
+];
+
+const GPS_TAGS: [&str; 2] = ["Exif.GPSInfo", "Xmp.exif.GPS*"];
+
+/// Width and height tags that describe the stored pixels and are rewritten to the output size when present.
+const DIMENSION_TAGS: [(&str, &str); 4] = [
+    ("Exif.Image.ImageWidth", "Exif.Image.ImageLength"),
+    ("Exif.Photo.PixelXDimension", "Exif.Photo.PixelYDimension"),
+    ("Xmp.exif.PixelXDimension", "Xmp.exif.PixelYDimension"),
+    ("Xmp.tiff.ImageWidth", "Xmp.tiff.ImageLength"),
+];
+
+const THUMBNAIL_GROUP: &str = "Exif.Thumbnail";
+
+/// Longest side of a regenerated EXIF thumbnail, the size cameras commonly embed.
+const THUMBNAIL_SIZE: u32 = 160;
+
+/// The metadata of the output: what `--metadata` and `--strip-gps` keep of the source, then the tags
+/// of the configured `[stamp]`. The encoders write no metadata of their own, so with `--metadata none` and
+/// no stamp there is nothing to write and `None` is returned. Save the result with `Metadata::save_to_file`.
+/// `target_path` is the file being written, `output_path` the name it ends up with.
+pub fn output_metadata(source_path: &Path, target_path: &str, output_path: &Path, img: &DynamicImage, args: &Args) -> Result<Option<Metadata>, String> {
+    let mut meta = if args.metadata == MetadataPolicy::None {
+        if args.stamp.is_none() {
+            return Ok(None);
+        }
+        Metadata::new_from_path(Path::new(target_path))?
+    } else {
+        let mut meta = Metadata::new_from_path(source_path)?;
+        carry_over(&mut meta, img, args)?;
+        meta
+    };
+    if let Some(stamp) = &args.stamp {
+        apply_stamp(&mut meta, stamp, output_path, img);
+    }
+    Ok(Some(meta))
+}
+
+/// The EXIF orientation (1 to 8) of an encoded image, `None` when it has none or its metadata can't be read.
+pub fn read_orientation(bytes: &[u8]) -> Option<u16> {
+    Metadata::new_from_buffer(bytes).ok()?.orientation()
+}
+
+/// Filters the source metadata, with the dimension tags and the embedded thumbnail brought in line with the output image `img`.
+fn carry_over(meta: &mut Metadata, img: &DynamicImage, args: &Args) -> Result<(), String> {
+    // the pixels were rotated upright on decode
+    if meta.orientation().is_some() {
+        meta.reset_orientation();
+    }
+    if args.strip_gps {
+        meta.delete_gps_info();
+    }
+    // the thumbnail tags are kept or dropped together with the thumbnail below
+    for tag in meta.tags().into_iter().filter(|tag| !tag.starts_with(THUMBNAIL_GROUP)) {
+        if !args.metadata.keeps(&tag) || (args.strip_gps && matches_any(&tag, &GPS_TAGS)) {
+            meta.clear_tag(&tag);
+        }
+    }
+    // blocks the backend can only keep or drop whole
+    for family in meta.opaque_families() {
+        if !args.metadata.keeps_all(family) {
+            meta.clear_tag(family);
+        }
+    }
+    for (width_tag, height_tag) in DIMENSION_TAGS {
+        if meta.has_tag(width_tag) || meta.has_tag(height_tag) {
+            meta.set_tag_numeric(width_tag, img.width())?;
+            meta.set_tag_numeric(height_tag, img.height())?;
+        }
+    }
+    // a thumbnail of the uncropped source would show a different picture, so it is replaced by one of the output
+    if meta.has_thumbnail() {
+        match thumbnail_jpeg(img).filter(|_| args.metadata.keeps(THUMBNAIL_GROUP)) {
+            Some(thumbnail) => meta.set_thumbnail(&thumbnail),
+            None => meta.erase_thumbnail()
+        }
+    }
+    Ok(())
+}
+
+/// Sets every tag of the stamp, with the placeholders of its values filled in for this image.
+/// List values are written as repeated IPTC datasets or XMP arrays, e.g. keywords; entries that end up empty are left out.
+/// A tag that can't be written is logged and skipped so the others still are.
+fn apply_stamp(meta: &mut Metadata, stamp: &Stamp, output_path: &Path, img: &DynamicImage) {
+    let placeholders = placeholders(stamp, output_path, img);
+    let render = |value: &str| placeholders.iter().fold(value.to_string(), |value, (key, replacement)| value.replace(&format!("{{{}}}", key), replacement));
+    if stamp.tags.keys().any(|tag| tag.starts_with("Iptc.")) {
+        // marks the IPTC strings as UTF-8 so symbols such as © survive
+        let _result = meta.set_tag_string("Iptc.Envelope.CharacterSet", "\u{1b}%G");
+    }
+    for (tag, value) in &stamp.tags {
+        let result = match value {
+            StampValue::One(value) => meta.set_tag_string(tag, &render(value)),
+            StampValue::Many(values) => {
+                let values: Vec<String> = values.iter().map(|value| render(value)).filter(|value| !value.is_empty()).collect();
+                meta.clear_tag(tag);
+                if values.is_empty() {
+                    Ok(())
+                } else {
+                    meta.set_tag_multiple_strings(tag, &values.iter().map(String::as_str).collect::<Vec<_>>())
+                }
+            }
+        };
+        if let Err(error) = result {
+            warn!("Unable to stamp {}: {}", tag, error);
+        }
+    }
+}
+
+/// `{name}`, `{stem}`, `{extension}`, `{width}` and `{height}` of the output, plus the named groups of the stamp's
+/// `pattern` matched against the file stem, e.g. `{sku}` for `(?<sku>[A-Z]+-[0-9]+)`. Groups that don't match are empty.
+fn placeholders(stamp: &Stamp, output_path: &Path, img: &DynamicImage) -> HashMap<String, String> {
+    let part = |part: Option<&OsStr>| part.and_then(OsStr::to_str).unwrap_or_default().to_string();
+    let stem = part(output_path.file_stem());
+    let mut placeholders = HashMap::from([
+        (String::from("name"), part(output_path.file_name())),
+        (String::from("extension"), part(output_path.extension())),
+        (String::from("width"), img.width().to_string()),
+        (String::from("height"), img.height().to_string()),
+    ]);
+    if let Some(StampPattern(pattern)) = &stamp.pattern {
+        let captures = pattern.captures(&stem);
+        if captures.is_none() {
+            debug!("Stamp pattern does not match '{}'", stem);
+        }
+        for name in pattern.capture_names().flatten() {
+            let value = captures.as_ref().and_then(|captures| captures.name(name)).map(|value| value.as_str()).unwrap_or_default();
+            placeholders.insert(name.to_string(), value.to_string());
+        }
+    }
+    placeholders.insert(String::from("stem"), stem);
+    placeholders
+}
+
+fn thumbnail_jpeg(img: &DynamicImage) -> Option<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let thumbnail = img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).into_rgb8();
+    thumbnail.write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, 80)).ok()?;
+    Some(buffer)
+}
+
+impl MetadataPolicy {
+    pub fn keeps(&self, tag: &str) -> bool {
+        match self {
+            MetadataPolicy::All => true,
+            MetadataPolicy::None => false,
+            MetadataPolicy::CopyrightOnly => matches_any(tag, &COPYRIGHT_TAGS),
+            MetadataPolicy::Keep(keys) => matches_any(tag, keys),
+            MetadataPolicy::Drop(keys) => !matches_any(tag, keys),
+        }
+    }
+
+    /// True when every tag of the family (`Exif`, `Iptc` or `Xmp`) is kept.
+    pub fn keeps_all(&self, family: &str) -> bool {
+        match self {
+            MetadataPolicy::All => true,
+            MetadataPolicy::None | MetadataPolicy::CopyrightOnly => false,
+            MetadataPolicy::Keep(keys) => keys.iter().any(|key| key == family),
+            MetadataPolicy::Drop(keys) => !keys.iter().any(|key| key.split('.').next() == Some(family)),
+        }
+    }
+}
+
+/// True when the tag is one of the keys or lies under one of them, e.g. `Exif.GPSInfo.GPSLatitude` under `Exif.GPSInfo`
+/// or `Iptc.Application2.Keywords` under `Iptc`. A trailing `*` matches any tag starting with the rest of the key.
+fn matches_any<S: AsRef<str>>(tag: &str, keys: &[S]) -> bool {
+    keys.iter().any(|key| match key.as_ref().strip_suffix('*') {
+        Some(prefix) => tag.starts_with(prefix),
+        None => tag == key.as_ref() || tag.strip_prefix(key.as_ref()).is_some_and(|rest| rest.starts_with('.'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::RgbImage;
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn keys_match_their_tag_and_everything_under_it() {
+        assert!(matches_any("Exif.GPSInfo.GPSLatitude", &["Exif.GPSInfo"]));
+        assert!(matches_any("Iptc.Application2.Keywords", &["Iptc"]));
+        assert!(matches_any("Exif.Image.Artist", &["Exif.Image.Artist"]));
+        assert!(!matches_any("Exif.Image.ArtistName", &["Exif.Image.Artist"]));
+        assert!(!matches_any("Exif.GPSInfoX.Tag", &["Exif.GPSInfo"]));
+        assert!(matches_any("Xmp.exif.GPSLatitude", &GPS_TAGS));
+        assert!(!matches_any("Xmp.exif.PixelXDimension", &GPS_TAGS));
+        assert!(matches_any("Xmp.xmpRights.UsageTerms", &COPYRIGHT_TAGS));
+    }
+
+    #[test]
+    fn policies_keep_the_tags_they_list() {
+        let keep: MetadataPolicy = "keep:Exif.Image,Xmp".parse().unwrap();
+        assert!(keep.keeps("Exif.Image.Make"));
+        assert!(!keep.keeps("Exif.Photo.ExposureTime"));
+        assert!(keep.keeps_all("Xmp"));
+        assert!(!keep.keeps_all("Exif"));
+        let drop: MetadataPolicy = "drop:Exif.Photo.MakerNote".parse().unwrap();
+        assert!(!drop.keeps("Exif.Photo.MakerNote"));
+        assert!(drop.keeps("Exif.Photo.ExposureTime"));
+        assert!(!drop.keeps_all("Exif"));
+        assert!(drop.keeps_all("Iptc"));
+        assert!(MetadataPolicy::CopyrightOnly.keeps("Iptc.Application2.Copyright"));
+        assert!(!MetadataPolicy::CopyrightOnly.keeps("Exif.GPSInfo.GPSLatitude"));
+    }
+
+    #[test]
+    fn dimension_tags_are_rewritten_to_the_output_size() {
+        let directory = std::env::temp_dir().join(format!("blipb-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&directory).unwrap();
+        let source = directory.join("source.jpg");
+        DynamicImage::ImageRgb8(RgbImage::new(200, 300)).save(&source).unwrap();
+        let mut meta = Metadata::new_from_path(&source).unwrap();
+        for (tag, value) in [("Exif.Image.ImageWidth", 200), ("Exif.Image.ImageLength", 300), ("Exif.Photo.PixelXDimension", 200)] {
+            meta.set_tag_numeric(tag, value).unwrap();
+        }
+        meta.save_to_file(&source).unwrap();
+
+        let img = DynamicImage::ImageRgb8(RgbImage::new(50, 70));
+        let meta = output_metadata(&source, "", Path::new("output.jpg"), &img, &Args::default()).unwrap().unwrap();
+        assert_eq!(meta.tag_string("Exif.Image.ImageWidth").as_deref(), Some("50"));
+        assert_eq!(meta.tag_string("Exif.Image.ImageLength").as_deref(), Some("70"));
+        assert_eq!(meta.tag_string("Exif.Photo.PixelXDimension").as_deref(), Some("50"));
+        assert_eq!(meta.tag_string("Exif.Photo.PixelYDimension").as_deref(), Some("70"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn placeholders_describe_the_output_and_the_pattern_groups() {
+        let stamp = Stamp { pattern: Some("^(?<sku>[A-Z]+-[0-9]+)_(?<color>[a-z]+)".parse().unwrap()), ..Stamp::default() };
+        let img = DynamicImage::ImageRgb8(RgbImage::new(50, 70));
+        let placeholders = placeholders(&stamp, Path::new("./output/AB-12_red.jpg"), &img);
+        assert_eq!(placeholders["name"], "AB-12_red.jpg");
+        assert_eq!(placeholders["stem"], "AB-12_red");
+        assert_eq!(placeholders["extension"], "jpg");
+        assert_eq!(placeholders["width"], "50");
+        assert_eq!(placeholders["height"], "70");
+        assert_eq!(placeholders["sku"], "AB-12");
+        assert_eq!(placeholders["color"], "red");
+    }
+
+    #[test]
+    fn unmatched_pattern_groups_are_empty() {
+        let stamp = Stamp { pattern: Some("^(?<sku>[A-Z]+-[0-9]+)".parse().unwrap()), ..Stamp::default() };
+        let img = DynamicImage::ImageRgb8(RgbImage::new(1, 1));
+        assert_eq!(placeholders(&stamp, Path::new("lowercase.png"), &img)["sku"], "");
+    }
+}
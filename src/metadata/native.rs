@@ -0,0 +1,352 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+use exif::experimental::Writer;
+use exif::{Context, Field, In, Reader, Tag, Value};
+use img_parts::jpeg::{markers, JpegSegment};
+use img_parts::png::PngChunk;
+use img_parts::{Bytes, DynImage, ImageEXIF};
+
+mod xmp;
+use xmp::Xmp;
+
+pub const BACKEND: &str = "native";
+
+const EXIF_SIGNATURE: &[u8] = b"Exif\0\0";
+const XMP_SIGNATURE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const PHOTOSHOP_SIGNATURE: &[u8] = b"Photoshop 3.0\0";
+/// Keyword of the PNG iTXt chunk holding the XMP packet.
+const XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+const CHUNK_ITXT: [u8; 4] = *b"iTXt";
+const CHUNK_IDAT: [u8; 4] = *b"IDAT";
+
+/// Tags that can be added when the source doesn't have them; tags already present can always be changed.
+const WRITABLE_TAGS: [Tag; 12] = [
+    Tag::Artist,
+    Tag::Copyright,
+    Tag::DateTime,
+    Tag::ImageDescription,
+    Tag::ImageLength,
+    Tag::ImageWidth,
+    Tag::Make,
+    Tag::Model,
+    Tag::Orientation,
+    Tag::PixelXDimension,
+    Tag::PixelYDimension,
+    Tag::Software,
+];
+
+/// Metadata of a JPEG or PNG read and written in pure Rust. EXIF and the simple and array properties of XMP are handled
+/// tag by tag with the exiv2 key names (`Exif.Image.Artist`, `Xmp.dc.title`, ...). The IPTC (Photoshop) block of a JPEG
+/// is carried over unchanged or dropped as a whole, and can't be stamped.
+pub struct Metadata {
+    fields: Vec<Field>,
+    little_endian: bool,
+    thumbnail: Option<Vec<u8>>,
+    xmp: Option<Xmp>,
+    iptc: Option<Bytes>,
+}
+
+pub fn initialize() -> Result<(), String> {
+    Ok(())
+}
+
+impl Metadata {
+    pub fn new_from_path(path: &Path) -> Result<Metadata, String> {
+        let bytes = fs::read(path).map_err(|error| error.to_string())?;
+        Metadata::new_from_bytes(Bytes::from(bytes))
+    }
+
+    pub fn new_from_buffer(bytes: &[u8]) -> Result<Metadata, String> {
+        Metadata::new_from_bytes(Bytes::copy_from_slice(bytes))
+    }
+
+    fn new_from_bytes(bytes: Bytes) -> Result<Metadata, String> {
+        let image = read_image(bytes)?;
+        let mut metadata = Metadata { fields: Vec::new(), little_endian: false, thumbnail: None, xmp: None, iptc: None };
+        if let Some(exif) = image.exif() {
+            let exif = Reader::new().read_raw(exif.to_vec()).map_err(|error| error.to_string())?;
+            metadata.thumbnail = thumbnail(&exif);
+            metadata.little_endian = exif.little_endian();
+            metadata.fields = exif.fields().cloned().collect();
+        }
+        let packet = match &image {
+            DynImage::Jpeg(jpeg) => {
+                metadata.iptc = segment_with_signature(jpeg.segments(), markers::APP13, PHOTOSHOP_SIGNATURE);
+                segment_with_signature(jpeg.segments(), markers::APP1, XMP_SIGNATURE).map(|xmp| xmp.slice(XMP_SIGNATURE.len()..))
+            }
+            DynImage::Png(png) => png.chunks_by_type(CHUNK_ITXT).find_map(xmp_text),
+            _ => None
+        };
+        metadata.xmp = packet.map(|packet| Xmp::parse(&packet)).transpose()?;
+        Ok(metadata)
+    }
+
+    pub fn tags(&self) -> Vec<String> {
+        let exif = self.fields.iter().map(|field| key(field.tag, field.ifd_num));
+        exif.chain(self.xmp.iter().flat_map(Xmp::keys)).collect()
+    }
+
+    pub fn tag_string(&self, tag: &str) -> Option<String> {
+        match tag {
+            "Iptc" => self.iptc.as_ref().map(|iptc| format!("Photoshop IPTC block, {} bytes", iptc.len())),
+            _ if tag.starts_with("Xmp.") => self.xmp.as_ref()?.get(tag),
+            _ => self.field(tag).map(|field| field.display_value().to_string())
+        }
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tag_string(tag).is_some()
+    }
+
+    /// Removes an EXIF tag or XMP property, or the whole XMP packet or IPTC block for `Xmp` or `Iptc`.
+    pub fn clear_tag(&mut self, tag: &str) {
+        match tag {
+            "Xmp" => self.xmp = None,
+            "Iptc" => self.iptc = None,
+            _ if tag.starts_with("Xmp.") => self.xmp.iter_mut().for_each(|xmp| xmp.remove(tag)),
+            _ => self.fields.retain(|field| key(field.tag, field.ifd_num) != tag)
+        }
+    }
+
+    pub fn set_tag_string(&mut self, tag: &str, value: &str) -> Result<(), String> {
+        match tag.starts_with("Xmp.") {
+            true => self.xmp.get_or_insert_with(Xmp::empty).set(tag, &[value]),
+            false => self.set_value(tag, Value::Ascii(vec![value.as_bytes().to_vec()]))
+        }
+    }
+
+    pub fn set_tag_numeric(&mut self, tag: &str, value: u32) -> Result<(), String> {
+        match tag.starts_with("Xmp.") {
+            true => self.xmp.get_or_insert_with(Xmp::empty).set(tag, &[&value.to_string()]),
+            false => self.set_value(tag, Value::Long(vec![value]))
+        }
+    }
+
+    /// Writes an XMP array; repeated EXIF and IPTC values need the exiv2 backend.
+    pub fn set_tag_multiple_strings(&mut self, tag: &str, values: &[&str]) -> Result<(), String> {
+        match tag.starts_with("Xmp.") {
+            true => self.xmp.get_or_insert_with(Xmp::empty).set(tag, values),
+            false => Err(format!("{} can only be written with the exiv2 backend", tag))
+        }
+    }
+
+    pub fn orientation(&self) -> Option<u16> {
+        let field = self.fields.iter().find(|field| field.tag == Tag::Orientation && field.ifd_num == In::PRIMARY)?;
+        field.value.get_uint(0).map(|orientation| orientation as u16)
+    }
+
+    pub fn reset_orientation(&mut self) {
+        let _result = self.set_value("Exif.Image.Orientation", Value::Short(vec![1]));
+    }
+
+    pub fn delete_gps_info(&mut self) {
+        self.fields.retain(|field| field.tag.context() != Context::Gps);
+    }
+
+    pub fn has_thumbnail(&self) -> bool {
+        self.thumbnail.is_some()
+    }
+
+    pub fn set_thumbnail(&mut self, jpeg: &[u8]) {
+        self.thumbnail = Some(jpeg.to_vec());
+    }
+
+    pub fn erase_thumbnail(&mut self) {
+        self.thumbnail = None;
+        self.fields.retain(|field| field.ifd_num != In::THUMBNAIL);
+    }
+
+    /// The IPTC block, when present, can only be kept or dropped as a whole.
+    pub fn opaque_families(&self) -> Vec<&'static str> {
+        match self.iptc {
+            Some(_) => vec!["Iptc"],
+            None => Vec::new()
+        }
+    }
+
+    /// Replaces the metadata of the image file at `path`. XMP is written into JPEG and PNG files, IPTC only into JPEG files.
+    /// An XMP packet without properties left is not written.
+    pub fn save_to_file(&self, path: &Path) -> Result<(), String> {
+        let bytes = fs::read(path).map_err(|error| error.to_string())?;
+        let mut image = read_image(Bytes::from(bytes))?;
+        let exif = self.exif()?;
+        let xmp = match &self.xmp {
+            Some(xmp) if !xmp.keys().is_empty() => Some(xmp.to_bytes()?),
+            _ => None
+        };
+        match &mut image {
+            DynImage::Jpeg(jpeg) => {
+                let segments = jpeg.segments_mut();
+                segments.retain(|segment| {
+                    !has_signature(segment, markers::APP1, EXIF_SIGNATURE)
+                        && !has_signature(segment, markers::APP1, XMP_SIGNATURE)
+                        && !has_signature(segment, markers::APP13, PHOTOSHOP_SIGNATURE)
+                });
+                // EXIF, XMP, then IPTC right after the JFIF header, where readers expect them
+                let exif = exif.map(|exif| Bytes::from([EXIF_SIGNATURE, &exif].concat()));
+                let xmp = xmp.map(|xmp| Bytes::from([XMP_SIGNATURE, &xmp].concat()));
+                let blocks = [(markers::APP13, &self.iptc), (markers::APP1, &xmp), (markers::APP1, &exif)];
+                let position = segments.iter().take_while(|segment| segment.marker() == markers::APP0).count();
+                for (marker, contents) in blocks {
+                    if let Some(contents) = contents {
+                        segments.insert(position, JpegSegment::new_with_contents(marker, contents.clone()));
+                    }
+                }
+            }
+            DynImage::Png(png) => {
+                png.set_exif(exif.map(Bytes::from));
+                let chunks = png.chunks_mut();
+                chunks.retain(|chunk| xmp_text(chunk).is_none());
+                if let Some(xmp) = xmp {
+                    // an uncompressed iTXt chunk without language, before the image data
+                    let contents = Bytes::from([XMP_KEYWORD, &[0, 0, 0, 0], &xmp].concat());
+                    let position = chunks.iter().position(|chunk| chunk.kind() == CHUNK_IDAT).unwrap_or(chunks.len() - 1);
+                    chunks.insert(position, PngChunk::new(CHUNK_ITXT, contents));
+                }
+            }
+            image => image.set_exif(exif.map(Bytes::from))
+        }
+        // encoded in full before the file is touched, so a failure leaves it as it was
+        let mut buffer = Vec::new();
+        image.encoder().write_to(&mut buffer).map_err(|error| error.to_string())?;
+        fs::write(path, buffer).map_err(|error| error.to_string())
+    }
+
+    fn field(&self, tag: &str) -> Option<&Field> {
+        self.fields.iter().find(|field| key(field.tag, field.ifd_num) == tag)
+    }
+
+    fn set_value(&mut self, tag: &str, value: Value) -> Result<(), String> {
+        if let Some(field) = self.fields.iter_mut().find(|field| key(field.tag, field.ifd_num) == tag) {
+            field.value = value;
+            return Ok(());
+        }
+        match WRITABLE_TAGS.iter().find(|known| key(**known, In::PRIMARY) == tag) {
+            Some(known) => {
+                self.fields.push(Field { tag: *known, ifd_num: In::PRIMARY, value });
+                Ok(())
+            }
+            None => Err(format!("{} can only be written with the exiv2 backend", tag))
+        }
+    }
+
+    /// The EXIF block as TIFF data, `None` when no tags of the main image are left.
+    fn exif(&self) -> Result<Option<Vec<u8>>, String> {
+        if !self.fields.iter().any(|field| field.ifd_num == In::PRIMARY) {
+            return Ok(None);
+        }
+        let mut writer = Writer::new();
+        for field in &self.fields {
+            writer.push_field(field);
+        }
+        if let Some(thumbnail) = &self.thumbnail {
+            writer.set_jpeg(thumbnail, In::THUMBNAIL);
+        }
+        let mut buffer = Cursor::new(Vec::new());
+        writer.write(&mut buffer, self.little_endian).map_err(|error| error.to_string())?;
+        Ok(Some(buffer.into_inner()))
+    }
+}
+
+fn read_image(bytes: Bytes) -> Result<DynImage, String> {
+    match DynImage::from_bytes(bytes) {
+        Ok(Some(image)) => Ok(image),
+        Ok(None) => Err(String::from("unsupported image format")),
+        Err(error) => Err(error.to_string())
+    }
+}
+
+/// The exiv2 key of a tag, e.g. `Exif.Photo.PixelXDimension`, or its number for tags unknown to kamadak-exif.
+fn key(tag: Tag, ifd_num: In) -> String {
+    let group = match tag.context() {
+        _ if ifd_num == In::THUMBNAIL => "Thumbnail",
+        Context::Tiff => "Image",
+        Context::Exif => "Photo",
+        Context::Gps => "GPSInfo",
+        Context::Interop => "Iop",
+        _ => "Unknown",
+    };
+    match tag.description() {
+        Some(_) => format!("Exif.{}.{}", group, tag),
+        None => format!("Exif.{}.0x{:04x}", group, tag.number())
+    }
+}
+
+fn thumbnail(exif: &exif::Exif) -> Option<Vec<u8>> {
+    let offset = exif.get_field(Tag::JPEGInterchangeFormat, In::THUMBNAIL)?.value.get_uint(0)? as usize;
+    let length = exif.get_field(Tag::JPEGInterchangeFormatLength, In::THUMBNAIL)?.value.get_uint(0)? as usize;
+    exif.buf().get(offset..offset + length).map(<[u8]>::to_vec)
+}
+
+fn segment_with_signature(segments: &[JpegSegment], marker: u8, signature: &[u8]) -> Option<Bytes> {
+    segments.iter().find(|segment| has_signature(segment, marker, signature)).map(|segment| segment.contents().clone())
+}
+
+/// The packet of an iTXt chunk holding XMP. Compressed packets, which the XMP specification advises against, are skipped.
+fn xmp_text(chunk: &PngChunk) -> Option<Bytes> {
+    if chunk.kind() != CHUNK_ITXT {
+        return None;
+    }
+    let contents = chunk.contents().slice(..);
+    let rest = contents.strip_prefix(XMP_KEYWORD)?;
+    let (compressed, rest) = rest.split_first()?;
+    if *compressed != 0 {
+        return None;
+    }
+    // the compression method, then the language tag and translated keyword, each ended by a null
+    let language = rest.get(1..)?;
+    let language_end = language.iter().position(|&byte| byte == 0)?;
+    let keyword = &language[language_end + 1..];
+    let keyword_end = keyword.iter().position(|&byte| byte == 0)?;
+    let start = contents.len() - keyword.len() + keyword_end + 1;
+    Some(contents.slice(start..))
+}
+
+fn has_signature(segment: &JpegSegment, marker: u8, signature: &[u8]) -> bool {
+    segment.marker() == marker && segment.contents().starts_with(signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, RgbImage};
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn exif_and_xmp_survive_a_save_and_load() {
+        let directory = std::env::temp_dir().join(format!("blipb-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&directory).unwrap();
+        for extension in ["jpg", "png"] {
+            let path = directory.join(format!("image.{}", extension));
+            DynamicImage::ImageRgb8(RgbImage::new(40, 30)).save(&path).unwrap();
+            let mut meta = Metadata::new_from_path(&path).unwrap();
+            meta.set_tag_string("Exif.Image.Artist", "Example Studio").unwrap();
+            meta.set_tag_numeric("Exif.Photo.PixelXDimension", 40).unwrap();
+            meta.set_tag_string("Xmp.dc.title", "AB-12").unwrap();
+            meta.set_tag_multiple_strings("Xmp.dc.subject", &["red", "catalog"]).unwrap();
+            meta.set_tag_numeric("Xmp.tiff.ImageWidth", 40).unwrap();
+            assert!(meta.set_tag_multiple_strings("Iptc.Application2.Keywords", &["red"]).is_err());
+            meta.save_to_file(&path).unwrap();
+
+            let mut meta = Metadata::new_from_path(&path).unwrap();
+            assert_eq!(meta.tags(), ["Exif.Image.Artist", "Exif.Photo.PixelXDimension", "Xmp.dc.title", "Xmp.dc.subject", "Xmp.tiff.ImageWidth"], "{}", extension);
+            assert_eq!(meta.tag_string("Exif.Image.Artist").as_deref(), Some("\"Example Studio\""));
+            assert_eq!(meta.tag_string("Xmp.dc.subject").as_deref(), Some("red, catalog"));
+            assert!(meta.has_tag("Xmp.tiff.ImageWidth"));
+            assert_eq!(image::image_dimensions(&path).unwrap(), (40, 30));
+
+            // a packet with no properties left is dropped
+            for tag in ["Xmp.dc.title", "Xmp.dc.subject", "Xmp.tiff.ImageWidth"] {
+                meta.clear_tag(tag);
+            }
+            meta.save_to_file(&path).unwrap();
+            let meta = Metadata::new_from_path(&path).unwrap();
+            assert!(meta.xmp.is_none(), "{}", extension);
+            assert_eq!(meta.tags(), ["Exif.Image.Artist", "Exif.Photo.PixelXDimension"]);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
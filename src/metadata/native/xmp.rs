@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+
+use quick_xml::events::{BytesEnd, BytesPI, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+
+const RDF_NAMESPACE: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+
+/// The exiv2 prefixes of the common namespaces. Keys use them (`Xmp.dc.title`) whatever prefix a packet declares.
+const NAMESPACES: [(&str, &str); 14] = [
+    ("aux", "http://ns.adobe.com/exif/1.0/aux/"),
+    ("crs", "http://ns.adobe.com/camera-raw-settings/1.0/"),
+    ("dc", "http://purl.org/dc/elements/1.1/"),
+    ("exif", "http://ns.adobe.com/exif/1.0/"),
+    ("exifEX", "http://cipa.jp/exif/1.0/"),
+    ("Iptc4xmpCore", "http://iptc.org/std/Iptc4xmpCore/1.0/xmlns/"),
+    ("Iptc4xmpExt", "http://iptc.org/std/Iptc4xmpExt/2008-02-29/"),
+    ("lr", "http://ns.adobe.com/lightroom/1.0/"),
+    ("photoshop", "http://ns.adobe.com/photoshop/1.0/"),
+    ("plus", "http://ns.useplus.org/ldf/xmp/1.0/"),
+    ("tiff", "http://ns.adobe.com/tiff/1.0/"),
+    ("xmp", "http://ns.adobe.com/xap/1.0/"),
+    ("xmpMM", "http://ns.adobe.com/xap/1.0/mm/"),
+    ("xmpRights", "http://ns.adobe.com/xap/1.0/rights/"),
+];
+
+/// Properties the XMP specification defines as arrays, with their array type. Other properties are written
+/// as plain text, or as a bag when they are given several values.
+const ARRAYS: [(&str, &str); 10] = [
+    ("Xmp.dc.contributor", "Bag"),
+    ("Xmp.dc.creator", "Seq"),
+    ("Xmp.dc.description", "Alt"),
+    ("Xmp.dc.publisher", "Bag"),
+    ("Xmp.dc.rights", "Alt"),
+    ("Xmp.dc.subject", "Bag"),
+    ("Xmp.dc.title", "Alt"),
+    ("Xmp.photoshop.SupplementalCategories", "Bag"),
+    ("Xmp.xmp.Identifier", "Bag"),
+    ("Xmp.xmpRights.UsageTerms", "Alt"),
+];
+
+/// An XMP packet parsed far enough to list, read, set and remove the top-level properties of its `rdf:Description`s,
+/// as text or arrays of text. Structures, qualifiers and the `xpacket` wrapper are kept and written back as they were.
+pub struct Xmp {
+    nodes: Vec<Node>,
+}
+
+enum Node {
+    Element(Element),
+    Text(String),
+    /// Comments, processing instructions, CDATA and declarations.
+    Other(Event<'static>),
+}
+
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Node>,
+}
+
+impl Element {
+    fn new(name: String) -> Element {
+        Element { name, attributes: Vec::new(), children: Vec::new() }
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|node| match node {
+            Node::Element(element) => Some(element),
+            _ => None
+        })
+    }
+
+    fn text(&self) -> String {
+        let text: String = self.children.iter().filter_map(|node| match node {
+            Node::Text(text) => Some(text.as_str()),
+            _ => None
+        }).collect();
+        text.trim().to_string()
+    }
+}
+
+impl Xmp {
+    /// An empty packet, for stamping images that have none.
+    pub fn empty() -> Xmp {
+        let mut description = Element::new(String::from("rdf:Description"));
+        description.attributes.push((String::from("rdf:about"), String::new()));
+        let mut rdf = Element::new(String::from("rdf:RDF"));
+        rdf.attributes.push((String::from("xmlns:rdf"), String::from(RDF_NAMESPACE)));
+        rdf.children.push(Node::Element(description));
+        let mut meta = Element::new(String::from("x:xmpmeta"));
+        meta.attributes.push((String::from("xmlns:x"), String::from("adobe:ns:meta/")));
+        meta.children.push(Node::Element(rdf));
+        let nodes = vec![
+            Node::Other(Event::PI(BytesPI::new("xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\""))),
+            Node::Element(meta),
+            Node::Other(Event::PI(BytesPI::new("xpacket end=\"w\""))),
+        ];
+        Xmp { nodes }
+    }
+
+    pub fn parse(packet: &[u8]) -> Result<Xmp, String> {
+        let text = std::str::from_utf8(packet).map_err(|error| format!("XMP packet: {}", error))?;
+        let mut reader = Reader::from_str(text.trim_end_matches('\0'));
+        // open elements, innermost last; the document itself is the first entry
+        let mut open = vec![Element::new(String::new())];
+        loop {
+            let event = reader.read_event().map_err(|error| format!("XMP packet: {}", error))?;
+            let node = match event {
+                Event::Start(start) => {
+                    open.push(element(&start)?);
+                    continue;
+                }
+                Event::End(_) => match open.pop() {
+                    Some(element) if !open.is_empty() => Node::Element(element),
+                    _ => return Err(String::from("XMP packet: unbalanced end tag"))
+                },
+                Event::Empty(start) => Node::Element(element(&start)?),
+                Event::Text(text) => Node::Text(text.unescape().map_err(|error| format!("XMP packet: {}", error))?.into_owned()),
+                Event::Eof => break,
+                event => Node::Other(event.into_owned())
+            };
+            if let Some(parent) = open.last_mut() {
+                parent.children.push(node);
+            }
+        }
+        match open.pop() {
+            Some(document) if open.is_empty() => Ok(Xmp { nodes: document.children }),
+            _ => Err(String::from("XMP packet: unclosed element"))
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut writer = Writer::new(Vec::new());
+        write(&mut writer, &self.nodes).map_err(|error| format!("XMP packet: {}", error))?;
+        Ok(writer.into_inner())
+    }
+
+    /// The exiv2 keys of the properties, e.g. `Xmp.dc.title`, in the order they appear.
+    pub fn keys(&self) -> Vec<String> {
+        let namespaces = self.namespaces();
+        let mut keys: Vec<String> = Vec::new();
+        for description in self.descriptions(&namespaces) {
+            let names = description.attributes.iter().map(|(name, _)| name).filter(|name| is_property(name, &namespaces));
+            for name in names.chain(description.elements().map(|element| &element.name)) {
+                let key = key_of(name, &namespaces);
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+        }
+        keys
+    }
+
+    /// The text of a property; the items of an array are joined with commas.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let namespaces = self.namespaces();
+        let rdf = prefix_of(RDF_NAMESPACE, &namespaces).unwrap_or("rdf");
+        for description in self.descriptions(&namespaces) {
+            if let Some((_, value)) = description.attributes.iter().find(|(name, _)| is_property(name, &namespaces) && key_of(name, &namespaces) == key) {
+                return Some(value.clone());
+            }
+            if let Some(property) = description.elements().find(|element| key_of(&element.name, &namespaces) == key) {
+                if let Some(resource) = property.attribute(&format!("{}:resource", rdf)) {
+                    return Some(resource.to_string());
+                }
+                return Some(match array(property, rdf) {
+                    Some(array) => array.elements().map(Element::text).collect::<Vec<_>>().join(", "),
+                    None => property.text()
+                });
+            }
+        }
+        None
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        let namespaces = self.namespaces();
+        for description in self.descriptions_mut(&namespaces) {
+            description.attributes.retain(|(name, _)| !is_property(name, &namespaces) || key_of(name, &namespaces) != key);
+            description.children.retain(|node| !matches!(node, Node::Element(element) if key_of(&element.name, &namespaces) == key));
+        }
+    }
+
+    /// Replaces a property with one value, or with an array of `values` when the property is an array or has several.
+    /// Alternatives (`dc:title`, `dc:rights`, ...) are written in the default language.
+    pub fn set(&mut self, key: &str, values: &[&str]) -> Result<(), String> {
+        let namespaces = self.namespaces();
+        let rdf = prefix_of(RDF_NAMESPACE, &namespaces).unwrap_or("rdf").to_string();
+        let (prefix, local_name) = key.strip_prefix("Xmp.").and_then(|key| key.split_once('.')).ok_or(format!("{} is not an XMP property", key))?;
+        let namespace = NAMESPACES.iter().find(|(known, _)| *known == prefix).map(|(_, namespace)| *namespace)
+            .or(namespaces.get(prefix).map(String::as_str))
+            .ok_or(format!("{}: unknown XMP namespace {}", key, prefix))?
+            .to_string();
+        let existing = self.descriptions(&namespaces).iter()
+            .flat_map(|description| description.elements())
+            .find(|element| key_of(&element.name, &namespaces) == key)
+            .and_then(|property| array(property, &rdf))
+            .map(|array| local(&array.name).to_string());
+        let kind = existing
+            .or(ARRAYS.iter().find(|(array, _)| *array == key).map(|(_, kind)| kind.to_string()))
+            .or((values.len() > 1).then(|| String::from("Bag")));
+
+        let declared = prefix_of(&namespace, &namespaces).map(str::to_string);
+        let mut property = Element::new(format!("{}:{}", declared.as_deref().unwrap_or(prefix), local_name));
+        match kind {
+            Some(kind) => {
+                let mut array = Element::new(format!("{}:{}", rdf, kind));
+                for value in values {
+                    let mut item = Element::new(format!("{}:li", rdf));
+                    if kind == "Alt" {
+                        item.attributes.push((String::from("xml:lang"), String::from("x-default")));
+                    }
+                    item.children.push(Node::Text(value.to_string()));
+                    array.children.push(Node::Element(item));
+                }
+                property.children.push(Node::Element(array));
+            }
+            None => property.children.push(Node::Text(values.concat()))
+        }
+
+        self.remove(key);
+        if self.descriptions(&namespaces).is_empty() {
+            let root = find_mut(&mut self.nodes, &format!("{}:RDF", rdf)).ok_or("XMP packet without rdf:RDF")?;
+            let mut description = Element::new(format!("{}:Description", rdf));
+            description.attributes.push((format!("{}:about", rdf), String::new()));
+            root.children.push(Node::Element(description));
+        }
+        let description = self.descriptions_mut(&namespaces).into_iter().next().ok_or("XMP packet without rdf:Description")?;
+        if declared.is_none() {
+            description.attributes.push((format!("xmlns:{}", prefix), namespace));
+        }
+        description.children.push(Node::Element(property));
+        Ok(())
+    }
+
+    /// Namespace URIs by the prefixes declared anywhere in the packet.
+    fn namespaces(&self) -> HashMap<String, String> {
+        let mut namespaces = HashMap::new();
+        let mut pending: Vec<&Node> = self.nodes.iter().collect();
+        while let Some(node) = pending.pop() {
+            if let Node::Element(element) = node {
+                for (name, value) in &element.attributes {
+                    if let Some(prefix) = name.strip_prefix("xmlns:") {
+                        namespaces.insert(prefix.to_string(), value.clone());
+                    }
+                }
+                pending.extend(element.children.iter());
+            }
+        }
+        namespaces
+    }
+
+    /// The top-level `rdf:Description`s, which hold the properties.
+    fn descriptions(&self, namespaces: &HashMap<String, String>) -> Vec<&Element> {
+        let rdf = prefix_of(RDF_NAMESPACE, namespaces).unwrap_or("rdf");
+        match find(&self.nodes, &format!("{}:RDF", rdf)) {
+            Some(root) => root.elements().filter(|element| element.name == format!("{}:Description", rdf)).collect(),
+            None => Vec::new()
+        }
+    }
+
+    fn descriptions_mut(&mut self, namespaces: &HashMap<String, String>) -> Vec<&mut Element> {
+        let rdf = prefix_of(RDF_NAMESPACE, namespaces).unwrap_or("rdf");
+        let description = format!("{}:Description", rdf);
+        match find_mut(&mut self.nodes, &format!("{}:RDF", rdf)) {
+            Some(root) => root.children.iter_mut().filter_map(|node| match node {
+                Node::Element(element) if element.name == description => Some(element),
+                _ => None
+            }).collect(),
+            None => Vec::new()
+        }
+    }
+}
+
+fn element(start: &BytesStart) -> Result<Element, String> {
+    let mut element = Element::new(String::from_utf8_lossy(start.name().as_ref()).into_owned());
+    for attribute in start.attributes() {
+        let attribute = attribute.map_err(|error| format!("XMP packet: {}", error))?;
+        let value = attribute.unescape_value().map_err(|error| format!("XMP packet: {}", error))?;
+        element.attributes.push((String::from_utf8_lossy(attribute.key.as_ref()).into_owned(), value.into_owned()));
+    }
+    Ok(element)
+}
+
+fn write(writer: &mut Writer<Vec<u8>>, nodes: &[Node]) -> std::io::Result<()> {
+    for node in nodes {
+        match node {
+            Node::Element(element) => {
+                let mut start = BytesStart::new(element.name.as_str());
+                for (name, value) in &element.attributes {
+                    start.push_attribute((name.as_str(), value.as_str()));
+                }
+                if element.children.is_empty() {
+                    writer.write_event(Event::Empty(start))?;
+                } else {
+                    writer.write_event(Event::Start(start))?;
+                    write(writer, &element.children)?;
+                    writer.write_event(Event::End(BytesEnd::new(element.name.as_str())))?;
+                }
+            }
+            Node::Text(text) => writer.write_event(Event::Text(BytesText::new(text)))?,
+            Node::Other(event) => writer.write_event(event.borrow())?
+        }
+    }
+    Ok(())
+}
+
+fn find<'a>(nodes: &'a [Node], name: &str) -> Option<&'a Element> {
+    nodes.iter().find_map(|node| match node {
+        Node::Element(element) if element.name == name => Some(element),
+        Node::Element(element) => find(&element.children, name),
+        _ => None
+    })
+}
+
+fn find_mut<'a>(nodes: &'a mut [Node], name: &str) -> Option<&'a mut Element> {
+    nodes.iter_mut().find_map(|node| match node {
+        Node::Element(element) => match element.name == name {
+            true => Some(element),
+            false => find_mut(&mut element.children, name)
+        },
+        _ => None
+    })
+}
+
+/// The `rdf:Bag`, `rdf:Seq` or `rdf:Alt` holding the items of an array property.
+fn array<'a>(property: &'a Element, rdf: &str) -> Option<&'a Element> {
+    property.elements().find(|element| ["Bag", "Seq", "Alt"].iter().any(|kind| element.name == format!("{}:{}", rdf, kind)))
+}
+
+/// Attributes of an `rdf:Description` that are properties rather than namespace declarations or RDF and XML attributes.
+fn is_property(name: &str, namespaces: &HashMap<String, String>) -> bool {
+    match name.split_once(':') {
+        Some((prefix, _)) => prefix != "xmlns" && prefix != "xml" && namespaces.get(prefix).map(String::as_str) != Some(RDF_NAMESPACE),
+        None => false
+    }
+}
+
+/// The exiv2 key of a qualified name, e.g. `Xmp.dc.title` for `dc:title`.
+fn key_of(name: &str, namespaces: &HashMap<String, String>) -> String {
+    let (prefix, local_name) = name.split_once(':').unwrap_or(("", name));
+    let prefix = namespaces.get(prefix)
+        .and_then(|namespace| NAMESPACES.iter().find(|(_, known)| known == namespace))
+        .map(|(known, _)| *known)
+        .unwrap_or(prefix);
+    format!("Xmp.{}.{}", prefix, local_name)
+}
+
+fn local(name: &str) -> &str {
+    name.split_once(':').map(|(_, local_name)| local_name).unwrap_or(name)
+}
+
+fn prefix_of<'a>(namespace: &str, namespaces: &'a HashMap<String, String>) -> Option<&'a str> {
+    namespaces.iter().find(|(_, declared)| *declared == namespace).map(|(prefix, _)| prefix.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKET: &str = r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:e="http://ns.adobe.com/exif/1.0/" xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:xmpMM="http://ns.adobe.com/xap/1.0/mm/" xmlns:stEvt="http://ns.adobe.com/xap/1.0/sType/ResourceEvent#"
+    e:PixelXDimension="4000" e:GPSLatitude="52,31.2N">
+   <e:GPSLongitude>13,24.6E</e:GPSLongitude>
+   <dc:subject><rdf:Bag><rdf:li>red</rdf:li><rdf:li>shoe</rdf:li></rdf:Bag></dc:subject>
+   <xmpMM:History><rdf:Seq><rdf:li stEvt:action="saved"/></rdf:Seq></xmpMM:History>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#;
+
+    #[test]
+    fn properties_are_read_set_and_removed_by_their_exiv2_keys() {
+        let mut xmp = Xmp::parse(PACKET.as_bytes()).unwrap();
+        assert_eq!(xmp.keys(), ["Xmp.exif.PixelXDimension", "Xmp.exif.GPSLatitude", "Xmp.exif.GPSLongitude", "Xmp.dc.subject", "Xmp.xmpMM.History"]);
+        assert_eq!(xmp.get("Xmp.exif.PixelXDimension").as_deref(), Some("4000"));
+        assert_eq!(xmp.get("Xmp.dc.subject").as_deref(), Some("red, shoe"));
+
+        xmp.remove("Xmp.exif.GPSLatitude");
+        xmp.remove("Xmp.exif.GPSLongitude");
+        xmp.set("Xmp.exif.PixelXDimension", &["640"]).unwrap();
+        xmp.set("Xmp.dc.title", &["AB-12 & co"]).unwrap();
+        xmp.set("Xmp.photoshop.Credit", &["Example Co."]).unwrap();
+        assert!(xmp.set("Xmp.unknown.Tag", &["value"]).is_err());
+
+        let xmp = Xmp::parse(&xmp.to_bytes().unwrap()).unwrap();
+        assert_eq!(xmp.keys(), ["Xmp.dc.subject", "Xmp.xmpMM.History", "Xmp.exif.PixelXDimension", "Xmp.dc.title", "Xmp.photoshop.Credit"]);
+        assert_eq!(xmp.get("Xmp.exif.PixelXDimension").as_deref(), Some("640"));
+        assert_eq!(xmp.get("Xmp.dc.title").as_deref(), Some("AB-12 & co"));
+        assert_eq!(xmp.get("Xmp.photoshop.Credit").as_deref(), Some("Example Co."));
+        let packet = String::from_utf8(xmp.to_bytes().unwrap()).unwrap();
+        // the packet's own prefix is reused, the title is an alternative in the default language, the history is untouched
+        assert!(packet.contains("<e:PixelXDimension>640</e:PixelXDimension>"));
+        assert!(packet.contains(r#"<dc:title><rdf:Alt><rdf:li xml:lang="x-default">AB-12 &amp; co</rdf:li></rdf:Alt></dc:title>"#));
+        assert!(packet.contains(r#"<rdf:li stEvt:action="saved"/>"#));
+        assert!(packet.starts_with("<?xpacket begin=") && packet.ends_with(r#"<?xpacket end="w"?>"#));
+    }
+}
@@ -1,112 +1,264 @@
+use std::borrow::Cow;
+use std::error::Error;
 use std::ffi::OsStr;
+use std::fs;
 use std::fs::{DirEntry, File};
-use std::io::BufWriter;
+use std::io::{BufReader, BufWriter};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::Path;
 
 use fraction::{Fraction, ToPrimitive};
-use image::{DynamicImage, ImageError};
-use rayon::prelude::*;
-use regex::Regex;
+use image::{ColorType, DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageResult};
+use regex::{NoExpand, Regex};
+use tracing::{debug, error, warn};
+use uuid::Uuid;
 
-use crate::imports::directory_to_files;
-use crate::structs::Args;
+use crate::background::{background_color, flatten};
+use crate::color::{convert_colors, embed_profile, icc_profile, output_profile, resize_linear, working_profile};
+use crate::decode::decode_image;
+use crate::metadata::output_metadata;
+use crate::scheduler::Scheduler;
+use crate::stages::{timed, Stage};
+use crate::state::{modified_and_len, Journal, State, StateEntry, PARTIAL_EXTENSION};
+use crate::resize::resize_simd;
+use crate::structs::{Args, OutputProfile, Resizer};
+use crate::trim::trim_image;
 
-use std::sync::atomic::Ordering;
-use atomic_float::AtomicF32;
-use image::codecs::jpeg::JpegEncoder;
-use image::codecs::png::PngEncoder;
+use std::sync::Mutex;
+use image::codecs::jpeg::{JpegDecoder, JpegEncoder};
+use image::codecs::png::{PngDecoder, PngEncoder};
 use image::error::{DecodingError, ImageFormatHint};
+use moxcms::ColorProfile;
 
-pub fn process_images(args: &Args, progress: &'static AtomicF32) {
-    let batch_size = args.batch_size;
-    let input = args.input.as_str();
-    let extensions: Vec<&str> = args.decode.split("|").collect();
-    let filtered_files = directory_to_files(&input, &extensions);
-    let count = filtered_files.iter().count();
-    let steps = 1.0 / count as f32;
-
-    filtered_files
-        .chunks(batch_size)
-        .for_each(|filtered_files_of_files| {
-            filtered_files_of_files
-                .par_iter()
-                .for_each(|file| {
-                    let x = progress.load(Ordering::SeqCst);
-                    progress.swap(x + steps, Ordering::SeqCst);
-                    process_image(file, &args);
-                })
-        });
-    progress.swap(1.0, Ordering::SeqCst);
+/// Processes one input and, when it succeeds, records it in the journal and the state so later runs can skip it.
+pub fn record_image(file: &std::io::Result<DirEntry>, args: &Args, settings_hash: &str, state: &Mutex<State>, journal: &Journal, scheduler: &Scheduler) {
+    let path = file.as_ref().unwrap().path();
+    if let Some(entry) = process_image_from_path(&path, args, settings_hash, scheduler) {
+        let file_name = path.file_name().unwrap().to_str().unwrap().to_string();
+        if let Err(error) = journal.append(&file_name, &entry) {
+            error!(file = file_name, "Unable to write journal: {}", error);
+        }
+        state.lock().unwrap().record(file_name, entry);
+    }
 }
 
-pub fn process_image(file: &std::io::Result<DirEntry>, args: &Args) {
-    let path = file.as_ref().unwrap().path();
-    process_image_from_path(&path, args);
+/// Rough peak memory for processing an image, from the dimensions and pixel format in its header: the decoded pixels
+/// and, while they are converted to `--color-profile`, the converted copy (gray sources become RGB). After that one
+/// full-size copy is counted for each stage that keeps its input alive: the trim, the crop, the two 16-bit buffers of
+/// `--linear-resize`, the resized image and the copy made for the output format, plus the 32-bit float copy
+/// `--resizer simd` samples from. Images that shrink are counted at full size, so this is an upper bound. Unreadable headers count as nothing and are left to fail on decode.
+pub fn estimate_memory(path: &Path, args: &Args, scheduler: &Scheduler) -> u64 {
+    let (width, height, color) = match scheduler.io(|| image_header(path)) {
+        Ok(header) => header,
+        Err(_) => return 0
+    };
+    let pixels = width as u64 * height as u64;
+    let channels = color.channel_count() as u64;
+    let sample_bytes = (color.bytes_per_pixel() / color.channel_count()) as u64;
+    let converted_channels = match args.color_profile {
+        OutputProfile::Source => channels,
+        _ if color.has_alpha() => channels.max(4),
+        _ => channels.max(3)
+    };
+    let decoded = pixels * channels * sample_bytes;
+    let working = pixels * converted_channels * sample_bytes;
+    let color_peak = if args.color_profile == OutputProfile::Source { decoded } else { decoded + working };
+    // the source, crop, resized and output copies, plus the trimmed one
+    let copies = 4 + u64::from(args.trim);
+    let linear = if args.linear_resize { 2 * pixels * converted_channels * 2 } else { 0 };
+    let simd = if args.resizer == Resizer::Simd { pixels * converted_channels * 4 } else { 0 };
+    color_peak.max(working * copies + linear + simd)
+}
+
+pub fn estimate_file_memory(file: &std::io::Result<DirEntry>, args: &Args, scheduler: &Scheduler) -> u64 {
+    file.as_ref().map(|entry| estimate_memory(&entry.path(), args, scheduler)).unwrap_or(0)
+}
+
+/// Width, height and pixel format as stored in the file, read from the header without decoding.
+fn image_header(path: &Path) -> ImageResult<(u32, u32, ColorType)> {
+    let reader = BufReader::new(File::open(path)?);
+    match ImageFormat::from_path(path)? {
+        ImageFormat::Png => header_of(PngDecoder::new(reader)?),
+        ImageFormat::Jpeg => header_of(JpegDecoder::new(reader)?),
+        _ => image::image_dimensions(path).map(|(width, height)| (width, height, ColorType::Rgba8))
+    }
+}
+
+fn header_of<'a>(decoder: impl ImageDecoder<'a>) -> ImageResult<(u32, u32, ColorType)> {
+    let (width, height) = decoder.dimensions();
+    Ok((width, height, decoder.color_type()))
 }
 
-pub fn process_image_from_path(path: &PathBuf, args: &Args) {
+/// Processes one input into the output directory. Returns the state entry of the content that was processed,
+/// or `None` when it failed.
+pub fn process_image_from_path(path: &Path, args: &Args, settings_hash: &str, scheduler: &Scheduler) -> Option<StateEntry> {
+    process_image_to(path, args, settings_hash, None, scheduler)
+}
+
+/// Like `process_image_from_path`, but writes the output to `target` instead of the output directory when given.
+/// The stamp placeholders still describe the file the output directory would get.
+pub fn process_image_to(path: &Path, args: &Args, settings_hash: &str, target: Option<&Path>, scheduler: &Scheduler) -> Option<StateEntry> {
     let file_extension = path.extension().and_then(OsStr::to_str);
     let file_name = path.file_name().unwrap().to_str().unwrap();
-    let _result = match file_extension {
-        None => (),
-        Some("jpg" | "jpeg" | "png") => process_image_to_disk(path, args, file_extension.unwrap()),
+    // error level so the file name stays on log messages at every verbosity
+    let _span = tracing::error_span!("image", file = file_name).entered();
+    match file_extension {
+        None => None,
+        Some("jpg" | "jpeg" | "png") => process_image_to_disk(path, args, file_extension.unwrap(), settings_hash, target, scheduler),
         Some(ext) => {
-            println!("{} | Image format '{}' not supported.", file_name, ext)
+            warn!("Image format '{}' not supported.", ext);
+            None
         }
-    };
+    }
 }
 
+#[cfg(feature = "ui")]
 pub fn process_image_in_memory(image: &Option<DynamicImage>, args: &Args, existing_extension: &str) -> Vec<u8> {
     match image {
         Some(img) => {
+            let img = &trim_image(img, args);
             let current_aspect = Fraction::from(img.width()) / Fraction::from(img.height());
             let img = &crop_image(img, current_aspect, args.aspect_ratio);
-            let img = &resize_image(img, args.max_width);
+            // the UI and preview work on sRGB pixels
+            let img = &resize_for_output(img, args.linear_resize.then(ColorProfile::new_srgb).as_ref(), args);
             let inner = Vec::new();
             let encode = args.encode.to_lowercase();
             let encode = encode.as_str();
             let new_extension = if encode == "original" { existing_extension } else { encode };
-            let buff = extension_to_encoder(inner, img, new_extension, args.quality);
-            let slice = buff.into_inner().unwrap();
-            slice
+            match extension_to_encoder(inner, &convert_for_format(img, new_extension, args), new_extension, args.quality) {
+                Ok(buff) => buff.into_inner().unwrap(),
+                Err(error) => {
+                    error!("Unable to encode image: {}", error);
+                    Vec::new()
+                }
+            }
         }
         None => Vec::new()
     }
 }
 
-pub fn load_image_from_vec(vec: &Vec<u8>) -> Option<DynamicImage> {
-    return match image::load_from_memory(vec) {
+#[cfg(feature = "ui")]
+pub fn load_image_from_vec(vec: &[u8]) -> Option<DynamicImage> {
+    match image::load_from_memory(vec) {
         Ok(dynamic_image) => Some(dynamic_image),
         Err(error) => {
-            println!("error [processing_image] {}", error);
+            error!("Unable to decode image: {}", error);
             None
         }
-    };
+    }
 }
 
 
-fn process_image_to_disk(path: &PathBuf, args: &Args, existing_extension: &str) {
+fn process_image_to_disk(path: &Path, args: &Args, existing_extension: &str, settings_hash: &str, target: Option<&Path>, scheduler: &Scheduler) -> Option<StateEntry> {
     let file_name = path.file_name().unwrap().to_str().unwrap();
-    let file_path = format!("{}{}", args.output, file_name);
-    let re_extension = Regex::new(r"\.[A-Za-z0-9]*$").unwrap();
-    let img = image::open(&path);
-    if img.is_ok() {
-        let img = &img.unwrap();
+    let source = scheduler.io(|| read_source(path))
+        .and_then(|(bytes, modified, len)| {
+            let entry = StateEntry::new(&bytes, modified, len, settings_hash);
+            let img = timed(Stage::Decode, || decode_image(&bytes, args)).map_err(|error| error.to_string())?;
+            let source_icc = icc_profile(&bytes);
+            let img = timed(Stage::Color, || convert_colors(img, source_icc.as_deref(), args))?;
+            let working_profile = args.linear_resize.then(|| working_profile(source_icc.as_deref(), args));
+            Ok((img, source_icc, working_profile, entry))
+        });
+    if let Err(error) = &source {
+        error!("Unable to read image: {}", error);
+    }
+    if let Ok((img, source_icc, working_profile, entry)) = source {
+        let img = timed(Stage::Trim, || trim_image(&img, args));
         let current_aspect = Fraction::from(img.width()) / Fraction::from(img.height());
-        let img = crop_image(&img, current_aspect, args.aspect_ratio);
-        let img = resize_image(&img, args.max_width);
+        let img = timed(Stage::Crop, || crop_image(&img, current_aspect, args.aspect_ratio));
+        let img = timed(Stage::Resize, || resize_for_output(&img, working_profile.as_ref(), args));
         let encode = args.encode.to_lowercase();
         let encode = encode.as_str();
         let new_extension = if encode == "original" { existing_extension } else { encode };
-        let new_file_path = re_extension.replace_all(file_path.as_str(), ".jpg").to_string(); //file_path.replace(".jpeg", ".jpg");
-        let inner = File::create(&new_file_path).unwrap();
-        let _buff = extension_to_encoder(inner, &img, new_extension, args.quality);
-        copy_metadata(path.to_str().unwrap(), new_file_path.as_str())
+        let output_path = format!("{}{}", args.output, output_file_name(file_name, new_extension));
+        let new_file_path = target.map(|target| target.display().to_string()).unwrap_or_else(|| output_path.clone());
+        // written to a uniquely named .partial file in the output directory and only renamed into place
+        // once encoding and metadata have both succeeded, so a failed or interrupted write never looks finished
+        let partial_file_path = format!("{}.{}.{}", new_file_path, Uuid::new_v4(), PARTIAL_EXTENSION);
+        let result = timed(Stage::Encode, || encode_image(&img, new_extension, args))
+            .and_then(|(encoded, color)| {
+                let profile = output_profile(source_icc.as_deref(), color, args)?;
+                scheduler.io(|| write_image_to_disk(path, &encoded, &img, profile.as_deref(), args, &partial_file_path, &output_path))
+            })
+            .and_then(|_| Ok(fs::rename(&partial_file_path, &new_file_path)?));
+        match result {
+            Ok(_) => {
+                debug!("Written to '{}'", new_file_path);
+                Some(entry)
+            }
+            Err(error) => {
+                error!("Unable to write '{}': {}", new_file_path, error);
+                let _result = fs::remove_file(&partial_file_path);
+                None
+            }
+        }
+    } else {
+        None
+    }
+}
+
+/// The contents of an input with its modification time and size, taken before it is read so that the state entry
+/// never pairs the time of a later change with the content processed here.
+fn read_source(path: &Path) -> Result<(Vec<u8>, u64, u64), String> {
+    let (modified, len) = modified_and_len(path).ok_or("unable to read the modification time")?;
+    let bytes = timed(Stage::Read, || fs::read(path)).map_err(|error| error.to_string())?;
+    Ok((bytes, modified, len))
+}
+
+/// The file name of the output: the source's, with the extension of the format it is encoded in (`jpeg` written as `jpg`).
+pub fn output_file_name(file_name: &str, new_extension: &str) -> String {
+    let re_extension = Regex::new(r"\.[A-Za-z0-9]*$").unwrap();
+    let extension = if new_extension == "jpeg" { "jpg" } else { new_extension };
+    re_extension.replace(file_name, NoExpand(&format!(".{}", extension))).to_string()
+}
+
+/// Encodes the image in memory in the format of `new_extension`, along with the color type of the pixels written.
+fn encode_image(img: &DynamicImage, new_extension: &str, args: &Args) -> Result<(Vec<u8>, ColorType), Box<dyn Error>> {
+    let img = convert_for_format(img, new_extension, args);
+    Ok((extension_to_encoder(Vec::new(), &img, new_extension, args.quality)?.into_inner()?, img.color()))
+}
+
+/// Writes the encoded image `img`, then its metadata; the caller holds an I/O permit.
+/// The color profile is embedded last, as the metadata backends may rewrite the file without it.
+fn write_image_to_disk(source_path: &Path, encoded: &[u8], img: &DynamicImage, profile: Option<&[u8]>, args: &Args, target_path: &str, output_path: &str) -> Result<(), Box<dyn Error>> {
+    timed(Stage::Write, || {
+        let mut file = File::create(target_path)?;
+        file.write_all(encoded)?;
+        file.sync_all()
+    })?;
+    // one run per image, so the benchmark's per-image figure covers the whole block
+    timed(Stage::Metadata, || -> Result<(), Box<dyn Error>> {
+        // unreadable or unsupported metadata leaves the output without it rather than failing the image,
+        // but a failed save may have left the file damaged
+        match output_metadata(source_path, target_path, Path::new(output_path), img, args) {
+            Ok(Some(meta)) => meta.save_to_file(Path::new(target_path))?,
+            Ok(None) => (),
+            Err(error) => warn!("Unable to copy metadata: {}", error)
+        }
+        if let Some(profile) = profile {
+            embed_profile(target_path, profile)?;
+        }
+        Ok(())
+    })?;
+    File::open(target_path)?.sync_all()?;
+    Ok(())
+}
+
+/// Resizes to `--max-width`, in linear light when a working profile is given for `--linear-resize`.
+/// Images that keep their size, or whose profile has no tone curves to undo, are resized as they are.
+pub fn resize_for_output(img: &DynamicImage, working_profile: Option<&ColorProfile>, args: &Args) -> DynamicImage {
+    let resize = |img: &DynamicImage| resize_image(img, args.max_width, args.resizer);
+    match working_profile {
+        Some(profile) if img.width() > args.max_width => resize_linear(img, profile, resize).unwrap_or_else(|error| {
+            warn!("Unable to resize in linear light: {}", error);
+            resize(img)
+        }),
+        _ => resize(img)
     }
 }
 
-pub fn resize_image(img: &DynamicImage, max_width: u32) -> DynamicImage {
+pub fn resize_image(img: &DynamicImage, max_width: u32, resizer: Resizer) -> DynamicImage {
     let max_width = max_width as f64;
     let current_width = img.width() as f64;
     let current_height = img.height() as f64;
@@ -114,7 +266,10 @@ pub fn resize_image(img: &DynamicImage, max_width: u32) -> DynamicImage {
     let new_width = if current_width > max_width { max_width } else { current_width } as u32;
     let new_height = if current_width > max_width { (max_width / current_width) * current_height } else { current_height } as u32;
 
-    img.resize_exact(new_width, new_height, image::imageops::FilterType::CatmullRom)
+    match resizer {
+        Resizer::Image => img.resize_exact(new_width, new_height, image::imageops::FilterType::CatmullRom),
+        Resizer::Simd => resize_simd(img, new_width, new_height)
+    }
 }
 
 pub fn crop_image(img: &DynamicImage, current_aspect: Fraction, new_aspect: Fraction) -> DynamicImage {
@@ -135,16 +290,35 @@ pub fn crop_image(img: &DynamicImage, current_aspect: Fraction, new_aspect: Frac
     }
 }
 
-pub fn copy_metadata(source_path: &str, target_path: &str) {
-    let meta = rexiv2::Metadata::new_from_path(source_path).unwrap();
-    meta.clear_tag("Exif.Image.ImageLength");
-    meta.clear_tag("Exif.Image.ImageWidth");
-    let _result = meta.save_to_file(target_path);
+/// Brings the pixels into what the output format can store. JPEG gets 8 bits per channel without alpha, transparency
+/// flattened onto `--background`. PNG keeps 16-bit samples and alpha unless `--force-8bit` or `--strip-alpha` ask
+/// otherwise; float samples, which it can't store, become 16-bit. Gray images stay gray unless they are flattened.
+pub fn convert_for_format<'a>(img: &'a DynamicImage, new_extension: &str, args: &Args) -> Cow<'a, DynamicImage> {
+    let lossless = new_extension == "png";
+    let sample_bytes = img.color().bytes_per_pixel() / img.color().channel_count();
+    let eight_bit = !lossless || args.force_8bit || sample_bytes == 1;
+    let strip_alpha = img.color().has_alpha() && (!lossless || args.strip_alpha);
+    if !strip_alpha && (sample_bytes == 1 || sample_bytes == 2 && !eight_bit) {
+        return Cow::Borrowed(img);
+    }
+    let img = if strip_alpha { flatten(img, background_color(img, args.background)) } else { img.clone() };
+    let gray = matches!(img, DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_) | DynamicImage::ImageLuma16(_) | DynamicImage::ImageLumaA16(_));
+    let alpha = img.color().has_alpha();
+    Cow::Owned(match (eight_bit, gray, alpha) {
+        (true, true, false) => DynamicImage::ImageLuma8(img.into_luma8()),
+        (true, true, true) => DynamicImage::ImageLumaA8(img.into_luma_alpha8()),
+        (true, false, false) => DynamicImage::ImageRgb8(img.into_rgb8()),
+        (true, false, true) => DynamicImage::ImageRgba8(img.into_rgba8()),
+        (false, true, false) => DynamicImage::ImageLuma16(img.into_luma16()),
+        (false, true, true) => DynamicImage::ImageLumaA16(img.into_luma_alpha16()),
+        (false, false, false) => DynamicImage::ImageRgb16(img.into_rgb16()),
+        (false, false, true) => DynamicImage::ImageRgba16(img.into_rgba16())
+    })
 }
 
-fn extension_to_encoder<W: Write>(inner: W, img: &DynamicImage, new_extension: &str, quality: u8) -> BufWriter<W> {
+fn extension_to_encoder<W: Write>(inner: W, img: &DynamicImage, new_extension: &str, quality: u8) -> Result<BufWriter<W>, ImageError> {
     let mut buff = BufWriter::new(inner);
-    let _result = match new_extension {
+    match new_extension {
         "png" => {
             let encoder = PngEncoder::new_with_quality(&mut buff, image::codecs::png::CompressionType::Best, image::codecs::png::FilterType::Adaptive);
             img.write_with_encoder(encoder)
@@ -158,7 +332,83 @@ fn extension_to_encoder<W: Write>(inner: W, img: &DynamicImage, new_extension: &
             let decoding_error =  DecodingError::from_format_hint(format_hint);
             Err(ImageError::Decoding(decoding_error))
         }
-    };
-    let _result = buff.flush().unwrap();
-    return buff;
+    }?;
+    buff.flush()?;
+    Ok(buff)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use crate::scheduler::Limits;
+
+    use super::*;
+
+    #[test]
+    fn outputs_take_the_extension_of_their_format() {
+        assert_eq!(output_file_name("photo.jpeg", "jpeg"), "photo.jpg");
+        assert_eq!(output_file_name("photo.JPG", "jpg"), "photo.jpg");
+        assert_eq!(output_file_name("scan.v2.png", "png"), "scan.v2.png");
+        assert_eq!(output_file_name("scan.png", "jpg"), "scan.jpg");
+    }
+
+    #[test]
+    fn each_format_gets_the_bit_depth_and_alpha_it_can_store() {
+        // half transparent red, so flattening onto the white default background shows
+        let rgba = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 3, Rgba([255, 0, 0, 128])));
+        let sources = [
+            DynamicImage::ImageRgb8(rgba.to_rgb8()),
+            rgba.clone(),
+            DynamicImage::ImageRgb16(rgba.to_rgb16()),
+            DynamicImage::ImageRgba16(rgba.to_rgba16()),
+            DynamicImage::ImageLuma16(rgba.to_luma16()),
+            DynamicImage::ImageLumaA16(rgba.to_luma_alpha16()),
+            DynamicImage::ImageRgba32F(rgba.to_rgba32f()),
+        ];
+        let expected = [
+            // jpg, png, png with --force-8bit, png with --strip-alpha
+            [ColorType::Rgb8, ColorType::Rgb8, ColorType::Rgb8, ColorType::Rgb8],
+            [ColorType::Rgb8, ColorType::Rgba8, ColorType::Rgba8, ColorType::Rgb8],
+            [ColorType::Rgb8, ColorType::Rgb16, ColorType::Rgb8, ColorType::Rgb16],
+            [ColorType::Rgb8, ColorType::Rgba16, ColorType::Rgba8, ColorType::Rgb16],
+            [ColorType::L8, ColorType::L16, ColorType::L8, ColorType::L16],
+            // flattening onto the background color makes gray images RGB
+            [ColorType::Rgb8, ColorType::La16, ColorType::La8, ColorType::Rgb16],
+            [ColorType::Rgb8, ColorType::Rgba16, ColorType::Rgba8, ColorType::Rgb16],
+        ];
+        let force_8bit = Args { force_8bit: true, ..Args::default() };
+        let strip_alpha = Args { strip_alpha: true, ..Args::default() };
+        for (source, expected) in sources.iter().zip(expected) {
+            let converted = [
+                convert_for_format(source, "jpg", &Args::default()).color(),
+                convert_for_format(source, "png", &Args::default()).color(),
+                convert_for_format(source, "png", &force_8bit).color(),
+                convert_for_format(source, "png", &strip_alpha).color(),
+            ];
+            assert_eq!(converted, expected, "{:?}", source.color());
+        }
+        assert!(matches!(convert_for_format(&rgba, "png", &Args::default()), Cow::Borrowed(_)));
+        let flattened = convert_for_format(&rgba, "jpg", &Args::default()).to_rgb8();
+        assert_eq!(flattened.get_pixel(0, 0).0, [255, 127, 127]);
+    }
+
+    #[test]
+    fn sixteen_bit_images_are_estimated_at_twice_the_memory() {
+        let directory = std::env::temp_dir().join(format!("blipb-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&directory).unwrap();
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(40, 30, Rgba([10, 20, 30, 255])));
+        let eight_bit = directory.join("eight.png");
+        let sixteen_bit = directory.join("sixteen.png");
+        image.save(&eight_bit).unwrap();
+        DynamicImage::ImageRgba16(image.to_rgba16()).save(&sixteen_bit).unwrap();
+
+        let args = Args::default();
+        let scheduler = Scheduler::new(Limits::from_args(&args));
+        assert_eq!(estimate_memory(&eight_bit, &args, &scheduler), 40 * 30 * 4 * 4);
+        assert_eq!(estimate_memory(&sixteen_bit, &args, &scheduler), 2 * estimate_memory(&eight_bit, &args, &scheduler));
+        let trim = Args { trim: true, ..Args::default() };
+        assert!(estimate_memory(&eight_bit, &trim, &scheduler) > estimate_memory(&eight_bit, &args, &scheduler));
+        fs::remove_dir_all(&directory).unwrap();
+    }
 }
@@ -0,0 +1,105 @@
+use fast_image_resize as fr;
+use fast_image_resize::images::Image;
+use fast_image_resize::{FilterType, PixelType, ResizeAlg, ResizeOptions};
+use image::{DynamicImage, ImageBuffer};
+
+/// Resizes with the SIMD (AVX2, SSE4.1 or NEON, whichever the CPU has) convolution of `fast_image_resize`,
+/// using the same CatmullRom filter as `DynamicImage::resize_exact`. Like it, every channel including alpha is resampled
+/// on its own, without premultiplying, and in 32-bit floats that are clamped only once both passes are done. Integer
+/// samples would clip the filter's overshoot at hard edges between the passes and leave them visibly different.
+/// Pixel layouts without a SIMD kernel fall back to `resize_exact`.
+pub fn resize_simd(img: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    match try_resize_simd(img, width, height) {
+        Some(resized) => resized,
+        None => img.resize_exact(width, height, image::imageops::FilterType::CatmullRom)
+    }
+}
+
+fn try_resize_simd(img: &DynamicImage, width: u32, height: u32) -> Option<DynamicImage> {
+    let samples: Vec<f32> = match img {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_) | DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => {
+            img.as_bytes().iter().map(|&sample| f32::from(sample)).collect()
+        }
+        DynamicImage::ImageLuma16(_) | DynamicImage::ImageLumaA16(_) | DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgba16(_) => {
+            img.as_bytes().chunks_exact(2).map(|pair| f32::from(u16::from_ne_bytes([pair[0], pair[1]]))).collect()
+        }
+        _ => return None
+    };
+    let pixel_type = match img.color().channel_count() {
+        1 => PixelType::F32,
+        2 => PixelType::F32x2,
+        3 => PixelType::F32x3,
+        _ => PixelType::F32x4
+    };
+    let bytes = samples.iter().flat_map(|sample| sample.to_ne_bytes()).collect();
+    drop(samples);
+    let source = Image::from_vec_u8(img.width(), img.height(), bytes, pixel_type).ok()?;
+    let mut target = Image::new(width, height, pixel_type);
+    let options = ResizeOptions::new().resize_alg(ResizeAlg::Convolution(FilterType::CatmullRom)).use_alpha(false);
+    fr::Resizer::new().resize(&source, &mut target, &options).ok()?;
+
+    let resized = target.into_vec();
+    let samples = resized.chunks_exact(4).map(|quad| f32::from_ne_bytes([quad[0], quad[1], quad[2], quad[3]]));
+    let to_u8 = || samples.clone().map(|sample| sample.clamp(0.0, f32::from(u8::MAX)).round() as u8).collect::<Vec<_>>();
+    let to_u16 = || samples.clone().map(|sample| sample.clamp(0.0, f32::from(u16::MAX)).round() as u16).collect::<Vec<_>>();
+    match img {
+        DynamicImage::ImageLuma8(_) => ImageBuffer::from_raw(width, height, to_u8()).map(DynamicImage::ImageLuma8),
+        DynamicImage::ImageLumaA8(_) => ImageBuffer::from_raw(width, height, to_u8()).map(DynamicImage::ImageLumaA8),
+        DynamicImage::ImageRgb8(_) => ImageBuffer::from_raw(width, height, to_u8()).map(DynamicImage::ImageRgb8),
+        DynamicImage::ImageRgba8(_) => ImageBuffer::from_raw(width, height, to_u8()).map(DynamicImage::ImageRgba8),
+        DynamicImage::ImageLuma16(_) => ImageBuffer::from_raw(width, height, to_u16()).map(DynamicImage::ImageLuma16),
+        DynamicImage::ImageLumaA16(_) => ImageBuffer::from_raw(width, height, to_u16()).map(DynamicImage::ImageLumaA16),
+        DynamicImage::ImageRgb16(_) => ImageBuffer::from_raw(width, height, to_u16()).map(DynamicImage::ImageRgb16),
+        DynamicImage::ImageRgba16(_) => ImageBuffer::from_raw(width, height, to_u16()).map(DynamicImage::ImageRgba16),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+
+    use super::*;
+
+    /// Gradients with noise, a blue channel and alpha that jump between their extremes, where the filter overshoots,
+    /// and transparent areas, the case premultiplying would change.
+    fn test_image() -> DynamicImage {
+        let mut state: u32 = 1;
+        DynamicImage::ImageRgba16(ImageBuffer::from_fn(301, 207, |x, y| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let noise = (state >> 24) as u16;
+            let alpha = if (x / 23 + y / 17) % 3 == 0 { 0 } else { u16::MAX };
+            let blue = if (x / 13 + y / 29) % 2 == 0 { 0 } else { u16::MAX };
+            Rgba([(x * 217) as u16 ^ noise, (y * 311) as u16, blue, alpha])
+        }))
+    }
+
+    /// Largest difference between the two resizers on the color channels and on alpha, in 8-bit levels.
+    fn max_difference(img: &DynamicImage) -> (f32, f32) {
+        let simd = resize_simd(img, 97, 61);
+        let reference = img.resize_exact(97, 61, image::imageops::FilterType::CatmullRom);
+        assert_eq!(simd.color(), reference.color());
+        let (simd, reference) = (simd.into_rgba32f(), reference.into_rgba32f());
+        simd.pixels().zip(reference.pixels()).fold((0.0, 0.0), |(color, alpha), (simd, reference)| {
+            let difference = |channel: usize| (simd[channel] - reference[channel]).abs() * 255.0;
+            (color.max(difference(0)).max(difference(1)).max(difference(2)), alpha.max(difference(3)))
+        })
+    }
+
+    #[test]
+    fn simd_matches_the_catmull_rom_resize() {
+        let img = test_image();
+        let variants = [
+            DynamicImage::ImageLuma8(img.to_luma8()),
+            DynamicImage::ImageRgb8(img.to_rgb8()),
+            DynamicImage::ImageRgba8(img.to_rgba8()),
+            DynamicImage::ImageRgb16(img.to_rgb16()),
+            DynamicImage::ImageRgba16(img.to_rgba16()),
+        ];
+        for variant in variants {
+            let (color, alpha) = max_difference(&variant);
+            assert!(color <= 1.5, "{:?} colors differ by {} levels", variant.color(), color);
+            assert!(alpha <= 1.5, "{:?} alpha differs by {} levels", variant.color(), alpha);
+        }
+    }
+}
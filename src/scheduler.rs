@@ -0,0 +1,161 @@
+use std::sync::{Condvar, Mutex, OnceLock};
+
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::structs::Args;
+
+/// Counting semaphore that bounds how many images, or how many bytes of decoded images, are being worked on at once.
+pub struct Permits {
+    available: Mutex<u64>,
+    released: Condvar,
+}
+
+pub struct Permit<'a> {
+    permits: &'a Permits,
+    count: u64,
+}
+
+/// How much work may run at once: CPU threads, images in flight, bytes of decoded images and concurrent file access.
+pub struct Limits {
+    pub threads: Option<usize>,
+    pub in_flight: usize,
+    pub memory: Option<u64>,
+    pub io_threads: Option<usize>,
+}
+
+/// The worker pool and I/O permits of one command, built once from its `Limits` and shared by every batch it runs,
+/// e.g. each set of files `watch` picks up.
+pub struct Scheduler {
+    limits: Limits,
+    pool: OnceLock<ThreadPool>,
+    io: Option<Permits>,
+}
+
+impl Limits {
+    pub fn from_args(args: &Args) -> Limits {
+        Limits {
+            threads: args.threads,
+            in_flight: args.batch_size,
+            memory: args.max_memory.map(|size| size.0),
+            io_threads: args.io_threads,
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new(limits: Limits) -> Scheduler {
+        let io = limits.io_threads.map(|io_threads| Permits::new(io_threads.max(1) as u64));
+        Scheduler { limits, pool: OnceLock::new(), io }
+    }
+
+    /// Runs a file read or write, waiting for an I/O permit first when `--io-threads` is set.
+    pub fn io<R>(&self, task: impl FnOnce() -> R) -> R {
+        let _permit = self.io.as_ref().map(|permits| tracing::info_span!("io_wait").in_scope(|| permits.acquire(1)));
+        task()
+    }
+
+    /// A dedicated pool rather than rayon's global one, sized by `--threads` or to every core.
+    /// It is started by the first batch, so commands that only read and write files never start it.
+    fn thread_pool(&self) -> &ThreadPool {
+        self.pool.get_or_init(|| {
+            ThreadPoolBuilder::new()
+                .num_threads(self.limits.threads.unwrap_or(0))
+                .thread_name(|index| format!("blipb-worker-{}", index))
+                .build()
+                .expect("Unable to start the worker threads.")
+        })
+    }
+}
+
+impl Permits {
+    pub fn new(count: u64) -> Permits {
+        Permits { available: Mutex::new(count), released: Condvar::new() }
+    }
+
+    /// Blocks until `count` permits are free. They are handed back when the returned guard is dropped.
+    pub fn acquire(&self, count: u64) -> Permit<'_> {
+        let mut available = self.available.lock().unwrap();
+        while *available < count {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= count;
+        Permit { permits: self, count }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.permits.available.lock().unwrap() += self.count;
+        self.permits.released.notify_all();
+    }
+}
+
+/// Runs `task` for every item as one stream of work on a dedicated pool, with at most `in_flight` items
+/// started but not yet finished. Unlike processing in chunks there is no barrier between batches,
+/// so a slow image only holds its own slot while the other workers keep pulling new ones.
+///
+/// With a `memory` budget each item also has to reserve its estimated `cost` in bytes before it starts,
+/// so large images run with less concurrency. An item costing more than the whole budget runs on its own.
+///
+/// The permits are taken on the calling thread, which only hands an item to the pool once it has them.
+/// A worker waiting for nested rayon work (the JPEG decoder is parallel) may pick up another item meanwhile,
+/// and that item must never block on permits held further down the same stack.
+pub fn for_each_bounded<T, C, F>(items: &[T], scheduler: &Scheduler, cost: C, task: F)
+    where T: Sync, C: Fn(&T) -> u64, F: Fn(&T) + Sync + Send {
+    let limits = &scheduler.limits;
+    let slots = Permits::new(limits.in_flight.max(1) as u64);
+    let budget = limits.memory.map(|bytes| (bytes, Permits::new(bytes)));
+    let task = &task;
+    scheduler.thread_pool().in_place_scope(|scope| {
+        for item in items {
+            let permits = tracing::info_span!("wait").in_scope(|| {
+                let slot = slots.acquire(1);
+                (slot, budget.as_ref().map(|(bytes, permits)| permits.acquire(cost(item).min(*bytes))))
+            });
+            scope.spawn(move |_| {
+                let _permits = permits;
+                task(item);
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use rayon::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn nested_parallel_work_within_a_budget_of_one_item_finishes() {
+        let items: Vec<u64> = (0..64).collect();
+        let scheduler = Scheduler::new(Limits { threads: Some(2), in_flight: 1, memory: Some(10), io_threads: None });
+        let done = AtomicUsize::new(0);
+        for_each_bounded(&items, &scheduler, |_| 100, |item| {
+            let sum: u64 = (0..10_000u64).into_par_iter().map(|value| value % (item + 1)).sum();
+            assert!(sum < 10_000 * (item + 1));
+            done.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(done.into_inner(), items.len());
+    }
+
+    #[test]
+    fn io_threads_bound_the_file_access_of_every_batch() {
+        let items: Vec<u64> = (0..32).collect();
+        let scheduler = Scheduler::new(Limits { threads: Some(4), in_flight: 8, memory: None, io_threads: Some(2) });
+        let (running, most) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        // the same scheduler, and so the same pool and permits, serves one batch after another
+        for _batch in 0..2 {
+            for_each_bounded(&items, &scheduler, |_| 0, |_| {
+                scheduler.io(|| {
+                    most.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    std::thread::sleep(std::time::Duration::from_millis(2));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                });
+            });
+        }
+        assert_eq!(most.into_inner(), 2);
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use tracing::{info_span, Span};
+
+/// Steps of `process_image_to_disk` whose time is accumulated across all worker threads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Read,
+    Decode,
+    Color,
+    Trim,
+    Crop,
+    Resize,
+    Encode,
+    Write,
+    Metadata,
+}
+
+pub const STAGES: [Stage; 9] = [Stage::Read, Stage::Decode, Stage::Color, Stage::Trim, Stage::Crop, Stage::Resize, Stage::Encode, Stage::Write, Stage::Metadata];
+
+static NANOS: [AtomicU64; 9] = [const { AtomicU64::new(0) }; 9];
+static COUNTS: [AtomicU64; 9] = [const { AtomicU64::new(0) }; 9];
+
+impl Stage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Read => "read",
+            Stage::Decode => "decode",
+            Stage::Color => "color",
+            Stage::Trim => "trim",
+            Stage::Crop => "crop",
+            Stage::Resize => "resize",
+            Stage::Encode => "encode",
+            Stage::Write => "write",
+            Stage::Metadata => "metadata",
+        }
+    }
+
+    // span names have to be static for tracing, hence one macro call per stage
+    fn span(self) -> Span {
+        match self {
+            Stage::Read => info_span!("read"),
+            Stage::Decode => info_span!("decode"),
+            Stage::Color => info_span!("color"),
+            Stage::Trim => info_span!("trim"),
+            Stage::Crop => info_span!("crop"),
+            Stage::Resize => info_span!("resize"),
+            Stage::Encode => info_span!("encode"),
+            Stage::Write => info_span!("write"),
+            Stage::Metadata => info_span!("metadata"),
+        }
+    }
+}
+
+/// Runs one stage inside its tracing span and adds its duration to the stage totals.
+pub fn timed<R>(stage: Stage, task: impl FnOnce() -> R) -> R {
+    let _span = stage.span().entered();
+    let start = Instant::now();
+    let result = task();
+    NANOS[stage as usize].fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    COUNTS[stage as usize].fetch_add(1, Ordering::Relaxed);
+    result
+}
+
+pub fn reset() {
+    NANOS.iter().chain(COUNTS.iter()).for_each(|value| value.store(0, Ordering::Relaxed));
+}
+
+/// Total time spent in the stage and how many times it ran since the last `reset`.
+pub fn totals(stage: Stage) -> (Duration, u64) {
+    let nanos = NANOS[stage as usize].load(Ordering::Relaxed);
+    (Duration::from_nanos(nanos), COUNTS[stage as usize].load(Ordering::Relaxed))
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{DirEntry, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Result, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use tracing::warn;
+
+use crate::structs::Args;
+
+const STATE_FILE_NAME: &str = ".blipb-state";
+const JOURNAL_FILE_NAME: &str = ".blipb-journal";
+pub const PARTIAL_EXTENSION: &str = "partial";
+
+/// What was known about an input the last time it was processed successfully.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateEntry {
+    pub content_hash: String,
+    /// Modification time in nanoseconds since the Unix epoch
+    pub modified: u64,
+    pub len: u64,
+    pub settings_hash: String,
+}
+
+/// Per-input record of previous runs, stored as `.blipb-state` in the output directory.
+#[derive(Default)]
+pub struct State {
+    entries: HashMap<String, StateEntry>,
+}
+
+/// Append-only log of the inputs finished during the current run, stored as `.blipb-journal`
+/// in the output directory and removed once the run completes.
+pub struct Journal {
+    file: Mutex<File>,
+}
+
+impl State {
+    pub fn load(output: &str) -> State {
+        let mut state = State::default();
+        for (file_name, entry) in read_entries(&Path::new(output).join(STATE_FILE_NAME)) {
+            state.entries.insert(file_name, entry);
+        }
+        state
+    }
+
+    /// The state a run starts from: empty with `--force`, plus the journal of an interrupted run with `--resume`.
+    pub fn load_for(args: &Args) -> State {
+        let mut state = if args.force { State::default() } else { State::load(&args.output) };
+        if args.resume {
+            for (file_name, entry) in read_entries(&Path::new(&args.output).join(JOURNAL_FILE_NAME)) {
+                state.entries.insert(file_name, entry);
+            }
+        }
+        state
+    }
+
+    pub fn save(&self, output: &str) -> Result<()> {
+        let path = Path::new(output).join(STATE_FILE_NAME);
+        let temp_path = Path::new(output).join(format!("{}.tmp", STATE_FILE_NAME));
+        let mut buff = BufWriter::new(File::create(&temp_path)?);
+        let mut file_names: Vec<&String> = self.entries.keys().collect();
+        file_names.sort();
+        for file_name in file_names {
+            writeln!(buff, "{}", format_entry(file_name, &self.entries[file_name]))?;
+        }
+        buff.flush()?;
+        fs::rename(temp_path, path)
+    }
+
+    /// True when the input was already processed with the same content and settings.
+    /// A matching modification time and size are trusted, otherwise the content is hashed and compared.
+    pub fn is_unchanged(&mut self, path: &Path, settings_hash: &str) -> bool {
+        let file_name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(file_name) => file_name,
+            None => return false
+        };
+        let entry = match self.entries.get_mut(file_name) {
+            Some(entry) if entry.settings_hash == settings_hash => entry,
+            _ => return false
+        };
+        let (modified, len) = match modified_and_len(path) {
+            Some(stamp) => stamp,
+            None => return false
+        };
+        if entry.modified == modified && entry.len == len {
+            return true;
+        }
+        match content_hash(path) {
+            Ok(hash) if hash == entry.content_hash => {
+                entry.modified = modified;
+                entry.len = len;
+                true
+            }
+            _ => false
+        }
+    }
+
+    pub fn record(&mut self, file_name: String, entry: StateEntry) {
+        self.entries.insert(file_name, entry);
+    }
+
+    /// Splits off the inputs that still need processing.
+    pub fn retain_changed(&mut self, files: Vec<Result<DirEntry>>, args: &Args) -> (Vec<Result<DirEntry>>, usize) {
+        let settings_hash = settings_hash(args);
+        let count = files.len();
+        let changed: Vec<_> = files
+            .into_iter()
+            .filter(|file| match file {
+                Ok(entry) => !self.is_unchanged(&entry.path(), &settings_hash),
+                Err(_) => true
+            })
+            .collect();
+        let skipped = count - changed.len();
+        (changed, skipped)
+    }
+}
+
+impl Journal {
+    /// Opens the journal for this run, continuing it with `--resume` and starting over otherwise.
+    /// The output directory is created if it doesn't exist yet, and leftover `.partial` outputs of an interrupted run
+    /// are removed either way.
+    pub fn start(args: &Args) -> Result<Journal> {
+        let output = Path::new(&args.output);
+        fs::create_dir_all(output)?;
+        let path = output.join(JOURNAL_FILE_NAME);
+        if !args.resume && path.exists() {
+            warn!("Discarding the journal of an interrupted run (use --resume to continue it).");
+        }
+        for entry in fs::read_dir(output)?.flatten() {
+            if entry.path().extension().and_then(|e| e.to_str()) == Some(PARTIAL_EXTENSION) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(args.resume)
+            .truncate(!args.resume)
+            .open(path)?;
+        Ok(Journal { file: Mutex::new(file) })
+    }
+
+    pub fn append(&self, file_name: &str, entry: &StateEntry) -> Result<()> {
+        let line = format!("{}\n", format_entry(file_name, entry));
+        self.file.lock().unwrap().write_all(line.as_bytes())
+    }
+
+    /// Removes the journal after the state file has been saved.
+    pub fn finish(self, output: &str) -> Result<()> {
+        drop(self.file);
+        fs::remove_file(Path::new(output).join(JOURNAL_FILE_NAME))
+    }
+}
+
+impl StateEntry {
+    /// The entry for an input processed from `bytes`, with the modification time and size taken before they were read.
+    /// A file replaced while it is processed then doesn't match and is hashed, and processed again, on the next run.
+    pub fn new(bytes: &[u8], modified: u64, len: u64, settings_hash: &str) -> StateEntry {
+        StateEntry {
+            content_hash: blake3::hash(bytes).to_hex().to_string(),
+            modified,
+            len,
+            settings_hash: settings_hash.to_string(),
+        }
+    }
+}
+
+/// Hash of every setting that changes the bytes written for an input.
+pub fn settings_hash(args: &Args) -> String {
+    let settings = format!("{}|{}|{}|{}|{}|{:?}|{}|{}|{:?}|{}|{:?}|{}|{}|{}|{}|{}|{}|{}", args.aspect_ratio, args.encode.to_lowercase(), args.max_width, args.quality, args.fast_decode, args.resizer, args.metadata, args.strip_gps, args.stamp, args.color_profile, args.embed_profile, args.linear_resize, args.background, args.force_8bit, args.strip_alpha, args.trim, args.trim_padding, args.trim_tolerance);
+    blake3::hash(settings.as_bytes()).to_hex().to_string()
+}
+
+// one line per input: content hash, modified nanoseconds, size, settings hash, file name
+fn format_entry(file_name: &str, entry: &StateEntry) -> String {
+    format!("{}\t{}\t{}\t{}\t{}", entry.content_hash, entry.modified, entry.len, entry.settings_hash, file_name)
+}
+
+fn read_entries(path: &Path) -> Vec<(String, StateEntry)> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Vec::new()
+    };
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| {
+            let mut fields = line.splitn(5, '\t');
+            match (fields.next(), fields.next().and_then(|m| m.parse().ok()), fields.next().and_then(|l| l.parse().ok()), fields.next(), fields.next()) {
+                (Some(content_hash), Some(modified), Some(len), Some(settings_hash), Some(file_name)) => Some((file_name.to_string(), StateEntry {
+                    content_hash: content_hash.to_string(),
+                    modified,
+                    len,
+                    settings_hash: settings_hash.to_string(),
+                })),
+                _ => None
+            }
+        })
+        .collect()
+}
+
+pub fn content_hash(path: &Path) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Modification time in nanoseconds and size, which together stand in for the content until either changes.
+pub fn modified_and_len(path: &Path) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((modified.as_nanos() as u64, metadata.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn output_directory() -> String {
+        let directory = std::env::temp_dir().join(format!("blipb-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&directory).unwrap();
+        format!("{}/", directory.display())
+    }
+
+    fn entry(modified: u64) -> StateEntry {
+        StateEntry { content_hash: String::from("c0ffee"), modified, len: 1234, settings_hash: String::from("5e771n65") }
+    }
+
+    #[test]
+    fn entries_round_trip_through_the_state_file() {
+        let output = output_directory();
+        let mut state = State::default();
+        state.record(String::from("b.jpg"), entry(1_700_000_000_123_456_789));
+        state.record(String::from("a name\twith tab.png"), entry(2));
+        state.save(&output).unwrap();
+        let loaded = State::load(&output);
+        assert_eq!(loaded.entries, state.entries);
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        let output = output_directory();
+        let line = format_entry("ok.jpg", &entry(3));
+        fs::write(Path::new(&output).join(STATE_FILE_NAME), format!("garbage\nhash\tnot-a-number\t1\ts\tx.jpg\n{}\n", line)).unwrap();
+        let loaded = State::load(&output);
+        assert_eq!(loaded.entries.len(), 1);
+        assert_eq!(loaded.entries["ok.jpg"], entry(3));
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn resume_picks_up_the_journal() {
+        let output = output_directory();
+        let args = Args { output: output.clone(), ..Args::default() };
+        let journal = Journal::start(&args).unwrap();
+        journal.append("done.jpg", &entry(4)).unwrap();
+        drop(journal);
+
+        assert!(State::load_for(&args).entries.is_empty());
+        let resumed = State::load_for(&Args { resume: true, ..args.clone() });
+        assert_eq!(resumed.entries["done.jpg"], entry(4));
+        // a completed run removes it
+        Journal::start(&args).unwrap().finish(&output).unwrap();
+        assert!(!Path::new(&output).join(JOURNAL_FILE_NAME).exists());
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn the_journal_creates_a_missing_output_directory() {
+        let output = format!("{}missing/", output_directory());
+        let journal = Journal::start(&Args { output: output.clone(), ..Args::default() }).unwrap();
+        journal.finish(&output).unwrap();
+        assert!(Path::new(&output).is_dir());
+        fs::remove_dir_all(Path::new(&output).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn a_changed_size_with_the_same_modification_time_is_hashed() {
+        let output = output_directory();
+        let input = Path::new(&output).join("input.jpg");
+        fs::write(&input, b"first").unwrap();
+        let (modified, len) = modified_and_len(&input).unwrap();
+        let mut state = State::default();
+        state.record(String::from("input.jpg"), StateEntry::new(b"first", modified, len, "settings"));
+        assert!(state.is_unchanged(&input, "settings"));
+        assert!(!state.is_unchanged(&input, "other settings"));
+
+        // same modification time, as `cp -p` or `rsync -t` leave it, but different content
+        fs::write(&input, b"second!").unwrap();
+        File::options().write(true).open(&input).unwrap().set_modified(UNIX_EPOCH + std::time::Duration::from_nanos(modified)).unwrap();
+        assert!(!state.is_unchanged(&input, "settings"));
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn a_file_replaced_while_it_was_processed_is_not_unchanged() {
+        let output = output_directory();
+        let input = Path::new(&output).join("input.jpg");
+        fs::write(&input, b"first").unwrap();
+        let (modified, len) = modified_and_len(&input).unwrap();
+        // the content that was processed, recorded with the stamp taken before it was read
+        let entry = StateEntry::new(b"first", modified, len, "settings");
+        fs::write(&input, b"other").unwrap();
+        File::options().write(true).open(&input).unwrap().set_modified(UNIX_EPOCH + std::time::Duration::from_nanos(modified + 1)).unwrap();
+        let mut state = State::default();
+        state.record(String::from("input.jpg"), entry);
+        assert!(!state.is_unchanged(&input, "settings"));
+        fs::remove_dir_all(&output).unwrap();
+    }
+}
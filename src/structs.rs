@@ -1,48 +1,551 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
 use std::path::PathBuf;
-use clap::Parser;
+use std::str::FromStr;
+use std::sync::Arc;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 use fraction::Fraction;
+use moxcms::ColorProfile;
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Parser, Debug, Clone, Default)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Enforced aspect ratio with center crop
-    #[arg(short, long, default_value = "5/7")]
+    #[arg(short, long, default_value = "5/7", global = true)]
     pub aspect_ratio: Fraction,
 
-    /// Batch sizes of images to process in parallel
-    #[arg(short, long, default_value = "100")]
+    /// Color that transparent areas are flattened onto when the output can't keep alpha: #RRGGBB, or auto to sample the image border
+    #[arg(long, default_value = "#FFFFFF", global = true)]
+    pub background: Background,
+
+    /// Maximum number of images being processed at once
+    #[arg(short, long, default_value = "100", global = true)]
     pub batch_size: usize,
 
+    /// Color profile of the output: srgb, display-p3, adobe-rgb, prophoto-rgb, the path of an .icc/.icm file, or source (no conversion)
+    #[arg(long, default_value = "srgb", global = true)]
+    pub color_profile: OutputProfile,
+
+    /// Configuration file to read settings from [default: blipb.toml, blipb.yaml or blipb.yml if present]
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
     /// Picture formats to read
-    #[arg(short, long, default_value = "jpg|jpeg|png")]
+    #[arg(short, long, default_value = "jpg|jpeg|png", global = true)]
     pub decode: String,
 
+    /// Whether to embed the ICC profile in the output: `auto` embeds every profile except sRGB, which viewers assume anyway
+    #[arg(long, value_enum, default_value = "auto", global = true)]
+    pub embed_profile: EmbedProfile,
+
     /// Picture formats to write (options; original, jpg, or png
-    #[arg(short, long, default_value = "original")]
+    #[arg(short, long, default_value = "original", global = true)]
     pub encode: String,
 
+    /// Decode large JPEGs at 1/2, 1/4 or 1/8 scale when the output is at most half their size (faster, near-identical output)
+    #[arg(long, global = true)]
+    pub fast_decode: bool,
+
+    /// Write 8 bits per channel even where the output format could keep 16
+    #[arg(long, global = true)]
+    pub force_8bit: bool,
+
+    /// Reprocess every input, ignoring the state file kept in the output directory
+    #[arg(long, global = true)]
+    pub force: bool,
+
     /// Input directory for source images
-    #[arg(short, long, default_value = "./input/")]
+    #[arg(short, long, default_value = "./input/", global = true)]
     pub input: String,
 
+    /// Maximum number of concurrent file reads and writes, e.g. 2 on network shares [default: unlimited]
+    #[arg(long, global = true)]
+    pub io_threads: Option<usize>,
+
+    /// Resize in linear light instead of gamma-encoded values, so fine high-contrast detail such as text keeps its brightness (slower)
+    #[arg(long, global = true)]
+    pub linear_resize: bool,
+
+    /// Log format on stderr: `text` or `json` (one object per line)
+    #[arg(long, value_enum, default_value = "text", global = true)]
+    pub log_format: LogFormat,
+
+    /// Memory budget for decoded images in flight, e.g. 6G or 512M; large images then run with less concurrency
+    #[arg(long, global = true)]
+    pub max_memory: Option<ByteSize>,
+
     /// Max width of image allowed before resizing.
-    #[arg(short, long, default_value = "1500")]
+    #[arg(short, long, default_value = "1500", global = true)]
     pub max_width: u32,
 
+    /// Metadata to copy from the source: all, none, copyright-only, keep:<keys> or drop:<keys> (comma separated, e.g. drop:Exif.Photo.MakerNote,Iptc)
+    #[arg(long, default_value = "all", global = true)]
+    pub metadata: MetadataPolicy,
+
     /// Output directory for processed images
-    #[arg(short, long, default_value = "./output/")]
+    #[arg(short, long, default_value = "./output/", global = true)]
     pub output: String,
 
+    /// Named preset from the configuration file, e.g. `[preset.web]`
+    #[arg(long, global = true)]
+    pub preset: Option<String>,
+
     /// JPEG quality
-    #[arg(short, long, default_value = "90")]
+    #[arg(short, long, default_value = "90", global = true)]
     pub quality: u8,
 
-    /// Initialize with UI (still under major development)
-    #[arg(long)]
-    pub ui: bool,
+    /// Log less: repeat for fewer messages (warnings only, then errors only, then nothing)
+    #[arg(long, action = ArgAction::Count, global = true)]
+    pub quiet: u8,
+
+    /// Resize implementation: `image` (portable) or `simd` (AVX2/NEON accelerated, same CatmullRom filter)
+    #[arg(long, value_enum, default_value = "image", global = true)]
+    pub resizer: Resizer,
+
+    /// Continue an interrupted run, skipping the inputs its journal records as finished
+    #[arg(long, global = true)]
+    pub resume: bool,
+
+    /// Tags written into every output, only set from the `[stamp]` table of a configuration file
+    #[arg(skip)]
+    pub stamp: Option<Stamp>,
+
+    /// Remove GPS location tags from the output, whatever --metadata keeps
+    #[arg(long, global = true)]
+    pub strip_gps: bool,
+
+    /// Flatten transparency onto --background even where the output format could keep alpha
+    #[arg(long, global = true)]
+    pub strip_alpha: bool,
+
+    /// Number of worker threads for image processing [default: one per core]
+    #[arg(long, global = true)]
+    pub threads: Option<usize>,
+
+    /// Record timing spans for every image and stage to this file: Chrome trace format if it ends in .json, folded flamegraph stacks otherwise
+    #[arg(long, global = true)]
+    pub trace_file: Option<PathBuf>,
+
+    /// Trim uniform borders (white, black or any color along the edges) before the aspect-ratio crop; disables --fast-decode scaling
+    #[arg(long, global = true)]
+    pub trim: bool,
+
+    /// Padding added back around the trimmed content, in percent of its width and height on each side
+    #[arg(long, value_name = "PERCENT", default_value = "0", global = true)]
+    pub trim_padding: f32,
+
+    /// Color distance (0-442, RGB Euclidean) up to which a pixel still counts as border when trimming
+    #[arg(long, value_name = "DISTANCE", default_value = "24", global = true)]
+    pub trim_tolerance: f32,
+
+    /// Log more: -v for debug messages, -vv for everything
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub verbose: u8,
 }
-#[derive(Default)]
-pub struct LoadedImage {
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Crop, resize and re-encode every image in the input directory (default)
+    Process,
+    /// Print the dimensions, format and metadata of an image
+    Inspect {
+        /// Image to inspect
+        file: PathBuf,
+    },
+    /// Render a single image with the current settings
+    Preview {
+        /// Image to render
+        file: PathBuf,
+
+        /// Where to write the rendered image [default: preview-<file name> in the output directory]
+        #[arg(long)]
+        to: Option<PathBuf>,
+    },
+    /// Watch the input directory and process new or modified images once they stop changing
+    Watch {
+        /// Milliseconds a file must stay unchanged before it is processed
+        #[arg(long, default_value = "2000")]
+        settle_ms: u64,
+    },
+    /// Launch the UI (still under major development)
+    #[cfg(feature = "ui")]
+    Ui,
+    /// Generate a synthetic image set, process it and report per-stage timings, throughput and peak memory
+    Bench {
+        /// Image sizes to generate, as WIDTHxHEIGHT separated by commas
+        #[arg(long, default_value = "3000x4000,6000x4000")]
+        sizes: String,
+
+        /// Picture formats to generate
+        #[arg(long, default_value = "jpg|png")]
+        formats: String,
+
+        /// Number of images to generate for every size and format
+        #[arg(long, default_value = "10")]
+        count: usize,
+
+        /// Seed for the generated pixels, so runs can be compared
+        #[arg(long, default_value = "1")]
+        seed: u64,
+
+        /// Keep the generated images and outputs instead of deleting them
+        #[arg(long)]
+        keep: bool,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+pub enum Resizer {
+    #[default]
+    Image,
+    Simd,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+pub enum EmbedProfile {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// The color space images are converted to, from the ICC profile embedded in the source (sRGB when there is none).
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum OutputProfile {
+    #[default]
+    Srgb,
+    DisplayP3,
+    AdobeRgb,
+    ProPhotoRgb,
+    /// Pixels are left as decoded, in the color space of the source
+    Source,
+    File(ProfileFile),
+}
+
+/// An `.icc`/`.icm` profile, read and validated once when the option is parsed and shared by every worker.
+#[derive(Debug, Clone)]
+pub struct ProfileFile {
     pub path: PathBuf,
-    pub file_name: String
-}
\ No newline at end of file
+    pub bytes: Arc<[u8]>,
+    pub profile: Arc<ColorProfile>,
+}
+
+impl ProfileFile {
+    pub fn load(path: PathBuf) -> Result<ProfileFile, String> {
+        let bytes = fs::read(&path).map_err(|error| format!("unable to read color profile {}: {}", path.display(), error))?;
+        let profile = ColorProfile::new_from_slice(&bytes).map_err(|error| format!("invalid color profile {}: {}", path.display(), error))?;
+        Ok(ProfileFile { path, bytes: Arc::from(bytes), profile: Arc::new(profile) })
+    }
+}
+
+impl PartialEq for ProfileFile {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path && self.bytes == other.bytes
+    }
+}
+
+impl FromStr for OutputProfile {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "srgb" => Ok(OutputProfile::Srgb),
+            "display-p3" => Ok(OutputProfile::DisplayP3),
+            "adobe-rgb" => Ok(OutputProfile::AdobeRgb),
+            "prophoto-rgb" => Ok(OutputProfile::ProPhotoRgb),
+            "source" => Ok(OutputProfile::Source),
+            path if path.ends_with(".icc") || path.ends_with(".icm") => ProfileFile::load(PathBuf::from(value.trim())).map(OutputProfile::File),
+            _ => Err(format!("unknown color profile '{}', expected srgb, display-p3, adobe-rgb, prophoto-rgb, source or an .icc/.icm file", value))
+        }
+    }
+}
+
+impl fmt::Display for OutputProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputProfile::Srgb => write!(f, "srgb"),
+            OutputProfile::DisplayP3 => write!(f, "display-p3"),
+            OutputProfile::AdobeRgb => write!(f, "adobe-rgb"),
+            OutputProfile::ProPhotoRgb => write!(f, "prophoto-rgb"),
+            OutputProfile::Source => write!(f, "source"),
+            OutputProfile::File(file) => write!(f, "{}", file.path.display())
+        }
+    }
+}
+
+/// An RGB color written as `#RRGGBB` (the `#` is optional).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct HexColor(pub [u8; 3]);
+
+impl FromStr for HexColor {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let digits = value.trim().trim_start_matches('#');
+        let channel = |index: usize| digits.get(index * 2..index * 2 + 2).and_then(|digits| u8::from_str_radix(digits, 16).ok());
+        match (digits.len(), channel(0), channel(1), channel(2)) {
+            (6, Some(red), Some(green), Some(blue)) => Ok(HexColor([red, green, blue])),
+            _ => Err(format!("invalid color '{}', expected #RRGGBB", value))
+        }
+    }
+}
+
+impl fmt::Display for HexColor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02X}{:02X}{:02X}", self.0[0], self.0[1], self.0[2])
+    }
+}
+
+/// What transparent areas are flattened onto: a fixed color, or `auto` for the color found along the image border.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Background {
+    Color(HexColor),
+    Auto,
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Color(HexColor([255, 255, 255]))
+    }
+}
+
+impl FromStr for Background {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "auto" => Ok(Background::Auto),
+            _ => HexColor::from_str(value).map(Background::Color).map_err(|_| format!("unknown background '{}', expected #RRGGBB or auto", value))
+        }
+    }
+}
+
+impl fmt::Display for Background {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Background::Color(color) => write!(f, "{}", color),
+            Background::Auto => write!(f, "auto")
+        }
+    }
+}
+
+/// A number of bytes given as plain bytes or with a K, M, G or T suffix (powers of 1024).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let unit = value[digits.len()..].to_uppercase();
+        let multiplier: u64 = match unit.trim_end_matches("IB").trim_end_matches('B') {
+            "" => 1,
+            "K" => 1 << 10,
+            "M" => 1 << 20,
+            "G" => 1 << 30,
+            "T" => 1 << 40,
+            _ => return Err(format!("unknown size unit '{}'", unit))
+        };
+        let amount: f64 = digits.trim().parse().map_err(|_| format!("invalid size '{}'", value))?;
+        Ok(ByteSize((amount * multiplier as f64) as u64))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let units = [("T", 1u64 << 40), ("G", 1 << 30), ("M", 1 << 20), ("K", 1 << 10)];
+        match units.iter().find(|(_, size)| self.0 >= *size && self.0.is_multiple_of(*size)) {
+            Some((unit, size)) => write!(f, "{}{}", self.0 / size, unit),
+            None => write!(f, "{}", self.0)
+        }
+    }
+}
+
+/// Which metadata of the source is carried over to the output. Keys are EXIF, IPTC or XMP tag names as used by exiv2,
+/// or a family or group of them such as `Iptc` or `Exif.GPSInfo`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum MetadataPolicy {
+    #[default]
+    All,
+    None,
+    CopyrightOnly,
+    Keep(Vec<String>),
+    Drop(Vec<String>),
+}
+
+impl FromStr for MetadataPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (policy, keys) = match value.split_once(':') {
+            Some((policy, keys)) => (policy, Some(keys)),
+            None => (value, None)
+        };
+        match (policy.trim().to_lowercase().as_str(), keys) {
+            ("all", None) => Ok(MetadataPolicy::All),
+            ("none", None) => Ok(MetadataPolicy::None),
+            ("copyright-only", None) => Ok(MetadataPolicy::CopyrightOnly),
+            ("keep", Some(keys)) => Ok(MetadataPolicy::Keep(parse_tag_keys(keys)?)),
+            ("drop", Some(keys)) => Ok(MetadataPolicy::Drop(parse_tag_keys(keys)?)),
+            _ => Err(format!("unknown metadata policy '{}', expected all, none, copyright-only, keep:<keys> or drop:<keys>", value))
+        }
+    }
+}
+
+impl fmt::Display for MetadataPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataPolicy::All => write!(f, "all"),
+            MetadataPolicy::None => write!(f, "none"),
+            MetadataPolicy::CopyrightOnly => write!(f, "copyright-only"),
+            MetadataPolicy::Keep(keys) => write!(f, "keep:{}", keys.join(",")),
+            MetadataPolicy::Drop(keys) => write!(f, "drop:{}", keys.join(","))
+        }
+    }
+}
+
+fn parse_tag_keys(keys: &str) -> Result<Vec<String>, String> {
+    let keys: Vec<String> = keys.split(',').map(|key| key.trim().to_string()).filter(|key| !key.is_empty()).collect();
+    match keys.iter().find(|key| !matches!(key.split('.').next(), Some("Exif" | "Iptc" | "Xmp"))) {
+        Some(key) => Err(format!("'{}' is not an Exif, Iptc or Xmp tag", key)),
+        None if keys.is_empty() => Err(String::from("no tags given")),
+        None => Ok(keys)
+    }
+}
+
+/// EXIF, IPTC or XMP tags stamped into every output, e.g. copyright, creator, credit line, usage terms or keywords.
+/// Values can use the placeholders `{name}`, `{stem}`, `{extension}`, `{width}`, `{height}` and the named groups of `pattern`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Stamp {
+    /// Regular expression matched against the file stem, whose named groups become placeholders, e.g. `^(?<sku>[A-Z]+-[0-9]+)`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<StampPattern>,
+    pub tags: BTreeMap<String, StampValue>,
+}
+
+/// The `pattern` of a stamp, compiled once when the configuration is loaded.
+#[derive(Clone)]
+pub struct StampPattern(pub Regex);
+
+impl FromStr for StampPattern {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Regex::new(value).map(StampPattern).map_err(|error| format!("invalid stamp pattern: {}", error))
+    }
+}
+
+impl PartialEq for StampPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+// written like the pattern string, which the settings hash is made of
+impl fmt::Debug for StampPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0.as_str())
+    }
+}
+
+impl Serialize for StampPattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for StampPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+/// A single value, or a list for repeatable tags such as `Iptc.Application2.Keywords` or `Xmp.dc.subject`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum StampValue {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_sizes_parse_with_binary_units() {
+        assert_eq!("512".parse(), Ok(ByteSize(512)));
+        assert_eq!("6G".parse(), Ok(ByteSize(6 << 30)));
+        assert_eq!("512mb".parse(), Ok(ByteSize(512 << 20)));
+        assert_eq!("1.5 GiB".parse(), Ok(ByteSize(3 << 29)));
+        assert_eq!("2T".parse(), Ok(ByteSize(2 << 40)));
+        assert!("6X".parse::<ByteSize>().is_err());
+        assert!("G".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn byte_sizes_display_in_the_largest_whole_unit() {
+        assert_eq!(ByteSize(6 << 30).to_string(), "6G");
+        assert_eq!(ByteSize(1536 << 20).to_string(), "1536M");
+        assert_eq!(ByteSize(1000).to_string(), "1000");
+        assert_eq!(ByteSize(0).to_string(), "0");
+    }
+
+    #[test]
+    fn metadata_policies_parse_and_round_trip() {
+        assert_eq!("all".parse(), Ok(MetadataPolicy::All));
+        assert_eq!("None".parse(), Ok(MetadataPolicy::None));
+        assert_eq!("copyright-only".parse(), Ok(MetadataPolicy::CopyrightOnly));
+        let policy: MetadataPolicy = "drop: Exif.Photo.MakerNote, Iptc".parse().unwrap();
+        assert_eq!(policy, MetadataPolicy::Drop(vec![String::from("Exif.Photo.MakerNote"), String::from("Iptc")]));
+        assert_eq!(policy.to_string().parse(), Ok(policy));
+    }
+
+    #[test]
+    fn metadata_policies_reject_bad_keys() {
+        assert!("keep:".parse::<MetadataPolicy>().is_err());
+        assert!("keep:Photo.Make".parse::<MetadataPolicy>().is_err());
+        assert!("all:Exif".parse::<MetadataPolicy>().is_err());
+        assert!("some".parse::<MetadataPolicy>().is_err());
+    }
+
+    #[test]
+    fn color_profile_files_are_loaded_when_parsed() {
+        assert_eq!("Display-P3".parse(), Ok(OutputProfile::DisplayP3));
+        assert!("missing.icc".parse::<OutputProfile>().unwrap_err().contains("unable to read"));
+        assert!("cmyk".parse::<OutputProfile>().is_err());
+    }
+
+    #[test]
+    fn hex_colors_parse_with_or_without_hash() {
+        assert_eq!("#FF8000".parse(), Ok(HexColor([255, 128, 0])));
+        assert_eq!("ff8000".parse(), Ok(HexColor([255, 128, 0])));
+        assert_eq!(HexColor([255, 128, 0]).to_string(), "#FF8000");
+        assert!("#FFF".parse::<HexColor>().is_err());
+        assert!("#GG0000".parse::<HexColor>().is_err());
+        assert!("#FF80001".parse::<HexColor>().is_err());
+    }
+
+    #[test]
+    fn backgrounds_are_a_color_or_auto() {
+        assert_eq!("AUTO".parse(), Ok(Background::Auto));
+        assert_eq!("#000000".parse(), Ok(Background::Color(HexColor([0, 0, 0]))));
+        assert_eq!(Background::default().to_string(), "#FFFFFF");
+        assert!("transparent".parse::<Background>().is_err());
+    }
+}
@@ -0,0 +1,25 @@
+use std::any::Any;
+use std::path::Path;
+
+use tracing_chrome::ChromeLayerBuilder;
+use tracing_flame::FlameLayer;
+use tracing_subscriber::{Layer, Registry};
+
+pub type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Records the spans around every stage to `--trace-file`: a Chrome trace (chrome://tracing, Perfetto)
+/// when the file name ends in `.json`, otherwise folded stacks for `inferno-flamegraph` or `flamegraph.pl`.
+/// The trace is complete once the returned guard is dropped at the end of `main`.
+pub fn layer(path: &Path) -> Result<(BoxedLayer, Box<dyn Any>), String> {
+    if is_json(path) {
+        let (layer, guard) = ChromeLayerBuilder::new().file(path).include_args(true).build();
+        Ok((layer.boxed(), Box::new(guard)))
+    } else {
+        let (layer, guard) = FlameLayer::with_file(path).map_err(|error| format!("unable to write trace file '{}': {}", path.display(), error))?;
+        Ok((layer.with_threads_collapsed(true).with_module_path(false).with_file_and_line(false).boxed(), Box::new(guard)))
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().and_then(|extension| extension.to_str()) == Some("json")
+}
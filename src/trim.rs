@@ -0,0 +1,91 @@
+use std::borrow::Cow;
+
+use image::{DynamicImage, GenericImageView};
+
+use crate::background::border_color;
+use crate::structs::Args;
+
+/// With `--trim`, cuts away the uniform margins around the content so the aspect crop frames the product rather than
+/// the backdrop. The backdrop is the color found along the border (see `border_color`) plus anything mostly transparent;
+/// pixels further than `--trim-tolerance` from it count as content. `--trim-padding` adds back that percentage of the
+/// content's width and height on each side, as far as the image reaches. Images without content are left whole.
+pub fn trim_image<'a>(img: &'a DynamicImage, args: &Args) -> Cow<'a, DynamicImage> {
+    if !args.trim {
+        return Cow::Borrowed(img);
+    }
+    match content_bounds(img, args.trim_tolerance) {
+        Some((left, top, right, bottom)) => {
+            let padding = args.trim_padding.max(0.0) / 100.0;
+            let pad_x = ((right - left + 1) as f32 * padding).round() as u32;
+            let pad_y = ((bottom - top + 1) as f32 * padding).round() as u32;
+            let (left, top) = (left.saturating_sub(pad_x), top.saturating_sub(pad_y));
+            let right = (right + pad_x).min(img.width() - 1);
+            let bottom = (bottom + pad_y).min(img.height() - 1);
+            if (left, top, right, bottom) == (0, 0, img.width() - 1, img.height() - 1) {
+                return Cow::Borrowed(img);
+            }
+            Cow::Owned(img.crop_imm(left, top, right - left + 1, bottom - top + 1))
+        }
+        None => Cow::Borrowed(img)
+    }
+}
+
+/// Left, top, right and bottom (inclusive) of the pixels that differ from the backdrop.
+fn content_bounds(img: &DynamicImage, tolerance: f32) -> Option<(u32, u32, u32, u32)> {
+    let (width, height) = img.dimensions();
+    let backdrop = border_color(img).map(|color| color.0.map(f32::from));
+    let is_content = |x: u32, y: u32| {
+        let pixel = img.get_pixel(x, y).0;
+        pixel[3] >= 128 && backdrop.is_none_or(|backdrop| {
+            let distance: f32 = (0..3).map(|channel| (pixel[channel] as f32 - backdrop[channel]).powi(2)).sum();
+            distance.sqrt() > tolerance
+        })
+    };
+    let top = (0..height).find(|&y| (0..width).any(|x| is_content(x, y)))?;
+    let bottom = (top..height).rev().find(|&y| (0..width).any(|x| is_content(x, y)))?;
+    let left = (0..width).find(|&x| (top..=bottom).any(|y| is_content(x, y)))?;
+    let right = (left..width).rev().find(|&x| (top..=bottom).any(|y| is_content(x, y)))?;
+    Some((left, top, right, bottom))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    /// A white backdrop with a dark product at x 5 to 8 and y 3 to 5, and a speck of near-white dust at (1, 1).
+    fn product() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(20, 10, |x, y| match (x, y) {
+            (5..=8, 3..=5) => Rgba([40, 30, 20, 255]),
+            (1, 1) => Rgba([250, 250, 250, 255]),
+            _ => Rgba([255, 255, 255, 255])
+        }))
+    }
+
+    #[test]
+    fn content_is_what_differs_from_the_backdrop_by_more_than_the_tolerance() {
+        assert_eq!(content_bounds(&product(), 10.0), Some((5, 3, 8, 5)));
+        assert_eq!(content_bounds(&product(), 5.0), Some((1, 1, 8, 5)));
+        // a transparent border has no backdrop color, so every visible pixel is content
+        let cutout = DynamicImage::ImageRgba8(RgbaImage::from_fn(20, 10, |x, y| Rgba([255, 255, 255, if (4..=6).contains(&x) && y == 7 { 255 } else { 0 }])));
+        assert_eq!(content_bounds(&cutout, 10.0), Some((4, 7, 6, 7)));
+        let blank = DynamicImage::ImageRgba8(RgbaImage::from_pixel(20, 10, Rgba([255, 255, 255, 255])));
+        assert_eq!(content_bounds(&blank, 10.0), None);
+    }
+
+    #[test]
+    fn trimming_keeps_the_content_and_its_padding_within_the_image() {
+        let img = product();
+        let dimensions = |args: &Args| trim_image(&img, args).dimensions();
+        let args = Args { trim: true, trim_tolerance: 10.0, ..Args::default() };
+        assert_eq!(dimensions(&Args { trim: false, ..args.clone() }), (20, 10));
+        assert_eq!(dimensions(&args), (4, 3));
+        assert_eq!(trim_image(&img, &args).get_pixel(0, 0).0, [40, 30, 20, 255]);
+        // 50% of 4x3 is 2 pixels on each side, 200% reaches past the top, left and bottom edges
+        assert_eq!(dimensions(&Args { trim_padding: 50.0, ..args.clone() }), (8, 7));
+        assert_eq!(dimensions(&Args { trim_padding: 200.0, ..args.clone() }), (17, 10));
+        let blank = DynamicImage::ImageRgba8(RgbaImage::from_pixel(20, 10, Rgba([255, 255, 255, 255])));
+        assert!(matches!(trim_image(&blank, &args), Cow::Borrowed(_)));
+    }
+}
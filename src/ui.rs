@@ -1,8 +1,8 @@
 use std::ffi::OsStr;
 use std::fs::DirEntry;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::Ordering;
 use std::thread;
 use std::time::Duration;
@@ -13,14 +13,21 @@ use eframe::egui::{Align, ColorImage, ImageData, Slider, SliderOrientation, Text
 use fraction::Fraction;
 use image::{DynamicImage, EncodableLayout};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::error;
 
+use crate::config::{self, ConfigFile, Settings};
+use crate::decode::open_upright;
 use crate::imports::directory_to_files;
-use crate::process::{load_image_from_vec, process_images, process_image_in_memory};
+use crate::process::{estimate_file_memory, load_image_from_vec, process_image_in_memory, record_image};
+use crate::scheduler::{for_each_bounded, Limits, Scheduler};
+use crate::state::{settings_hash, Journal, State};
 use crate::structs::Args;
 
 pub fn run(settings: Args) {
     let native_options = eframe::NativeOptions {
         renderer: Renderer::Wgpu,
+        persist_window: true,
         ..Default::default()
     };
     let _ = eframe::run_native("Batched Lazy Image Processing Binary", native_options, Box::new(|cc| Box::new(App::new(cc, settings))));
@@ -28,6 +35,49 @@ pub fn run(settings: Args) {
 
 static PROGRESS: AtomicF32 = AtomicF32::new(0.0);
 
+fn process_images(args: &Args, progress: &'static AtomicF32) {
+    let input = args.input.as_str();
+    let extensions: Vec<&str> = args.decode.split("|").collect();
+    let filtered_files = directory_to_files(&input, &extensions);
+    let mut state = State::load_for(args);
+    let (filtered_files, _skipped) = state.retain_changed(filtered_files, args);
+    let settings_hash = settings_hash(args);
+    let state = Mutex::new(state);
+    let journal = match Journal::start(args) {
+        Ok(journal) => journal,
+        Err(error) => {
+            error!("Unable to start the journal in the output directory '{}': {}", args.output, error);
+            progress.swap(1.0, Ordering::SeqCst);
+            return;
+        }
+    };
+    let count = filtered_files.iter().count();
+    let steps = 1.0 / count as f32;
+
+    // leave a core free for the UI thread unless --threads says otherwise
+    let mut limits = Limits::from_args(args);
+    let cores = std::thread::available_parallelism().map(|cores| cores.get()).unwrap_or(1);
+    limits.threads.get_or_insert(cores.saturating_sub(1).max(1));
+    let scheduler = Scheduler::new(limits);
+    for_each_bounded(&filtered_files, &scheduler, |file| estimate_file_memory(file, args, &scheduler), |file| {
+        record_image(file, args, &settings_hash, &state, &journal, &scheduler);
+        progress.fetch_add(steps, Ordering::SeqCst);
+    });
+    let _result = state.into_inner().unwrap().save(&args.output).and_then(|_| journal.finish(&args.output));
+    progress.swap(1.0, Ordering::SeqCst);
+}
+
+/// Settings restored from the previous session through `cc.storage`. The window size is persisted by eframe itself.
+#[derive(Serialize, Deserialize)]
+struct SavedSettings {
+    input: String,
+    output: String,
+    aspect_ratio: String,
+    jpeg_quality: u32,
+    target_max_width: u32,
+    preview: bool,
+}
+
 struct App {
     jpeg_quality: u32,
     target_max_width: u32,
@@ -50,16 +100,39 @@ struct App {
     source_texture: Option<TextureHandle>,
     target_texture: Option<TextureHandle>,
     update: bool,
+    settings: Args,
 }
 
 impl App {
     fn new(cc: &eframe::CreationContext<'_>, settings: Args) -> Self {
         // Customize egui here with cc.egui_ctx.set_fonts and cc.egui_ctx.set_visuals.
-        // Restore app state using cc.storage (requires the "persistence" feature).
         // Use the cc.gl (a glow::Context) to create graphics shaders and buffers that you can use
         // for e.g. egui::PaintCallback.
 
         egui_extras::install_image_loaders(&cc.egui_ctx);
+        let mut settings = settings;
+        let saved: Option<SavedSettings> = cc.storage.and_then(|storage| eframe::get_value(storage, eframe::APP_KEY));
+        let preview = saved.as_ref().map(|saved| saved.preview).unwrap_or(false);
+        // options typed on the command line or set by the configuration file win over the previous session,
+        // and a folder that is gone is not restored
+        if let Some(saved) = saved {
+            let restore = |id: &str| !config::given_explicitly(id);
+            if restore("input") && Path::new(&saved.input).is_dir() {
+                settings.input = saved.input;
+            }
+            if restore("output") {
+                settings.output = saved.output;
+            }
+            if restore("aspect_ratio") {
+                settings.aspect_ratio = Fraction::from_str(saved.aspect_ratio.as_str()).unwrap_or(settings.aspect_ratio);
+            }
+            if restore("quality") {
+                settings.quality = saved.jpeg_quality as u8;
+            }
+            if restore("max_width") {
+                settings.max_width = saved.target_max_width;
+            }
+        }
         let input_directory = settings.input.as_str();
         let extensions: Vec<&str> = settings.decode.split("|").collect();
         let files = directory_to_files(input_directory, &extensions);
@@ -71,7 +144,7 @@ impl App {
             let e = path.extension();
             existing_extension = String::from(e.and_then(OsStr::to_str).unwrap());
             let file_name = path.file_name().map(|s| s.to_os_string().into_string().unwrap());
-            let source_image = match image::open(&path) {
+            let source_image = match open_upright(&path) {
                 Ok(image) => Some(image),
                 Err(_) => None
             };
@@ -90,9 +163,9 @@ impl App {
             decode: settings.decode.clone(),
             encode: settings.encode.clone(),
             existing_extension,
-            preview: false,
+            preview,
             input: settings.input.clone(),
-            output: settings.output,
+            output: settings.output.clone(),
             files,
             file_count,
             file_selected: 1,
@@ -102,11 +175,24 @@ impl App {
             source_texture: None,
             target_texture: None,
             update: true,
+            settings,
         }
     }
 }
 
 impl eframe::App for App {
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        let saved = SavedSettings {
+            input: self.input.clone(),
+            output: self.output.clone(),
+            aspect_ratio: self.aspect_ratio.clone(),
+            jpeg_quality: self.jpeg_quality,
+            target_max_width: self.target_max_width,
+            preview: self.preview,
+        };
+        eframe::set_value(storage, eframe::APP_KEY, &saved);
+    }
+
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
         egui::CentralPanel::default().show(ctx, |ui| {
             let frame_size = frame.info().window_info.size;
@@ -145,7 +231,7 @@ impl eframe::App for App {
                                     self.source_file_name = file_name;
                                     self.source_path = Some(path.clone());
                                     self.preview = false;
-                                    self.source_image = match image::open(path) {
+                                    self.source_image = match open_upright(&path) {
                                         Ok(image) => Some(image),
                                         Err(_) => None
                                     };
@@ -166,7 +252,7 @@ impl eframe::App for App {
                                 let file_name = path.file_name().map(|s| s.to_os_string().into_string().unwrap());
                                 self.source_file_name = file_name;
                                 self.source_path = Some(path.clone());
-                                self.source_image = match image::open(path) {
+                                self.source_image = match open_upright(&path) {
                                     Ok(image) => Some(image),
                                     Err(_) => {
                                         self.preview = false;
@@ -241,7 +327,7 @@ impl eframe::App for App {
                                 self.source_path = Some(path);
                                 if self.source_file_name.is_some() && self.source_path.is_some() {
                                     self.source_path.iter().for_each(|path| {
-                                        self.source_image = match image::open(path) {
+                                        self.source_image = match open_upright(path) {
                                             Ok(image) => Some(image),
                                             Err(_) => None
                                         };
@@ -274,6 +360,19 @@ impl eframe::App for App {
                         ui.add_enabled(false, button);
                     }
 
+                    if ui.button("Export preset...").clicked() {
+                        if let Some(path) = rfd::FileDialog::new()
+                            .set_file_name("blipb.toml")
+                            .add_filter("TOML", &["toml"])
+                            .add_filter("YAML", &["yaml", "yml"])
+                            .save_file() {
+                            let config = ConfigFile::new(Settings::from_args(&build_args_from_app(self)));
+                            if let Err(error) = config::save(&path, &config) {
+                                error!("Unable to export preset: {}", error);
+                            }
+                        }
+                    }
+
                     ui.add(egui::ProgressBar::new(PROGRESS.load(Ordering::SeqCst)).show_percentage());
                     if PROGRESS.load(Ordering::SeqCst) < 1.0 && PROGRESS.load(Ordering::SeqCst) > 0.0 {
                         ctx.request_repaint_after(Duration::from_secs(1));
@@ -350,6 +449,6 @@ fn build_args_from_app(app: &mut App) -> Args {
         max_width: app.target_max_width,
         output: app.output.clone(),
         quality: app.jpeg_quality as u8,
-        ui: true,
+        ..app.settings.clone()
     }
 }
\ No newline at end of file
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use notify::{EventKind, RecursiveMode, Watcher};
+use tracing::{error, info};
+
+use crate::imports::path_extension_filter;
+use crate::process::{estimate_memory, process_image_from_path};
+use crate::scheduler::{for_each_bounded, Limits, Scheduler};
+use crate::state::{settings_hash, State};
+use crate::structs::Args;
+
+/// Size and modification time of a file, compared between polls to tell when it has stopped changing.
+#[derive(PartialEq)]
+struct Snapshot {
+    len: u64,
+    modified: SystemTime,
+}
+
+struct Pending {
+    snapshot: Snapshot,
+    since: Instant,
+}
+
+/// Watches the input directory and processes new or modified images once they have stopped changing for `settle`.
+pub fn run(args: &Args, settle: Duration) {
+    let extensions: Vec<&str> = args.decode.split("|").collect();
+    let settings_hash = settings_hash(args);
+    let state = Mutex::new(State::load_for(args));
+    let scheduler = Scheduler::new(Limits::from_args(args));
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).expect("Unable to start watching for file changes.");
+    watcher
+        .watch(Path::new(&args.input), RecursiveMode::NonRecursive)
+        .expect("Unable to watch the input directory.");
+    println!("Watching {} for new images (Ctrl+C to stop).", args.input);
+    // stops after the images in progress, so the state is saved and `main` can finish the --trace-file;
+    // a second Ctrl+C exits right away
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_handler = Arc::clone(&stop);
+    if let Err(error) = ctrlc::set_handler(move || {
+        if stop_handler.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+    }) {
+        error!("Unable to handle Ctrl+C: {}", error);
+    }
+
+    // a floor so that --settle-ms 0 doesn't spin
+    let poll = settle.clamp(Duration::from_millis(50), Duration::from_millis(500));
+    let mut pending: HashMap<PathBuf, Pending> = HashMap::new();
+    while !stop.load(Ordering::SeqCst) {
+        match receiver.recv_timeout(poll) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    event.paths
+                        .into_iter()
+                        .filter(|path| path_extension_filter(path, &extensions))
+                        .for_each(|path| {
+                            if let Some(snapshot) = snapshot(&path) {
+                                pending.insert(path, Pending { snapshot, since: Instant::now() });
+                            }
+                        });
+                }
+            }
+            Ok(Err(error)) => error!("Unable to watch the input directory: {}", error),
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => break
+        }
+
+        let mut settled = Vec::new();
+        pending.retain(|path, waiting| {
+            if waiting.since.elapsed() < settle {
+                return true;
+            }
+            match snapshot(path) {
+                None => false,
+                Some(current) if current != waiting.snapshot => {
+                    waiting.snapshot = current;
+                    waiting.since = Instant::now();
+                    true
+                }
+                Some(_) => {
+                    settled.push(path.clone());
+                    false
+                }
+            }
+        });
+        if settled.is_empty() {
+            continue;
+        }
+
+        settled.retain(|path| !state.lock().unwrap().is_unchanged(path, &settings_hash));
+        for_each_bounded(&settled, &scheduler, |path| estimate_memory(path, args, &scheduler), |path| {
+            if let Some(entry) = process_image_from_path(path, args, &settings_hash, &scheduler) {
+                info!(file = %path.display(), "Processed");
+                let file_name = path.file_name().unwrap().to_str().unwrap().to_string();
+                state.lock().unwrap().record(file_name, entry);
+            }
+        });
+        if let Err(error) = state.lock().unwrap().save(&args.output) {
+            error!("Unable to save processing state: {}", error);
+        }
+    }
+    println!("Stopped watching {}.", args.input);
+}
+
+fn snapshot(path: &Path) -> Option<Snapshot> {
+    let metadata = fs::metadata(path).ok()?;
+    Some(Snapshot { len: metadata.len(), modified: metadata.modified().ok()? })
+}